@@ -0,0 +1,141 @@
+// battery saves: the prg ram of carts with a battery, kept in a <rom>.sav file between runs
+//
+// the file is just the ram, same as every other emulator does it. it gets written to a .tmp file
+// next to it first and then renamed over the old one, so crashing halfway through a write can't
+// eat the save
+//
+// the frontend hands it the ram after every frame, and whatever it saw last gets written when it's
+// dropped, so the save survives the frontend bailing out with an error or a panic
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::nes::Nes;
+
+#[derive(Debug)]
+pub struct BatterySave {
+    path: PathBuf,
+    // what's in the file right now, so unchanged ram doesn't get written again
+    saved: Vec<u8>,
+    // the ram as of the last update
+    ram: Vec<u8>,
+}
+
+impl BatterySave {
+    /// Where the save for a rom goes: next to it, or in `save_dir` if there is one
+    pub fn path_for(rom_path: &Path, save_dir: Option<&Path>) -> PathBuf {
+        let mut name = rom_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".sav");
+        match save_dir {
+            Some(dir) => dir.join(name),
+            None => rom_path.with_file_name(name),
+        }
+    }
+
+    /// Loads the save into the console if there is one. None if the cart has no battery, so
+    /// there's nothing to save
+    pub fn open(path: PathBuf, nes: &mut Nes) -> anyhow::Result<Option<Self>> {
+        if nes.battery_ram().is_none() {
+            return Ok(None);
+        }
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("couldn't read {}", path.display()));
+            }
+        };
+        nes.load_battery_ram(&data);
+        // a new save doesn't get written until the game actually puts something in it
+        let saved = nes.battery_ram().unwrap_or_default().to_vec();
+        Ok(Some(BatterySave {
+            path,
+            ram: saved.clone(),
+            saved,
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keeps a copy of the console's ram, for writing out later
+    pub fn update(&mut self, nes: &Nes) {
+        if let Some(ram) = nes.battery_ram() {
+            self.ram.clear();
+            self.ram.extend_from_slice(ram);
+        }
+    }
+
+    /// Writes the ram out if it changed since the last time. gives back whether it wrote
+    /// anything
+    pub fn flush(&mut self, nes: &Nes) -> anyhow::Result<bool> {
+        self.update(nes);
+        self.write()
+    }
+
+    fn write(&mut self) -> anyhow::Result<bool> {
+        if self.ram == self.saved {
+            return Ok(false);
+        }
+
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("couldn't make {}", dir.display()))?;
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        (|| -> std::io::Result<()> {
+            let mut f = File::create(&tmp)?;
+            f.write_all(&self.ram)?;
+            // make sure it's really on the disk before it replaces the old save
+            f.sync_all()?;
+            std::fs::rename(&tmp, &self.path)
+        })()
+        .with_context(|| format!("couldn't write {}", self.path.display()))?;
+
+        self.saved.clone_from(&self.ram);
+        Ok(true)
+    }
+}
+
+impl Drop for BatterySave {
+    fn drop(&mut self) {
+        if let Err(e) = self.write() {
+            eprintln!("{e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_on_drop() {
+        // ines header with the battery bit set
+        let mut rom = b"NES\x1a\x01\x01\x02\x00".to_vec();
+        rom.resize(16, 0);
+        rom.extend(vec![0; 0x4000 + 0x2000]);
+        let mut nes = Nes::from_rom(&rom).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("nes-battery-{}", std::process::id()));
+        let path = dir.join("game.nes.sav");
+        let mut battery = BatterySave::open(path.clone(), &mut nes).unwrap().unwrap();
+        // nothing to write while the ram is still blank
+        assert!(!battery.flush(&nes).unwrap());
+        assert!(!path.exists());
+
+        nes.load_battery_ram(&[1, 2, 3]);
+        battery.update(&nes);
+        drop(battery);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(data.len(), nes.battery_ram().unwrap().len());
+        assert_eq!(data[..4], [1, 2, 3, 0]);
+    }
+}
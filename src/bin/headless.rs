@@ -0,0 +1,205 @@
+// runs a rom without a window, for ci and golden image tests
+//
+// usage: nes-headless [options] <rom>
+//   --frames N          how many frames to run (default 60), or the most to wait with --blargg
+//   --blargg            run a blargg test rom until it reports a result through $6000, then
+//                       print the result instead of the frame hash and exit with 1 if it failed
+//   --input FILE        input script, see below (it's followed with --blargg too)
+//   --screenshot FILE   write the last frame, as png if the name ends in .png and ppm otherwise
+//   --ram FILE          dump the cpu's internal ram
+//   --vram FILE         dump the ppu's address space
+//   --palette NAME      palette for the screenshot, a preset (default, rgb, ntsc) or a .pal file
+//   --hue, --saturation, --contrast, --brightness
+//                       knobs for the generated ntsc palette
+//   --model NAME        front-loader (default), top-loader or famicom, which changes what reset
+//                       does to the ppu
+//   --region NAME       ntsc, pal or dendy instead of what the rom's header says
+//   --gamedb FILE       NES 2.0 xml database (nes20db.xml) to fix bad rom headers with, the
+//                       built in one still gets used for games that aren't in it
+//
+// the crc32 of the last frame's palette indices is printed to stdout, so it doesn't change with
+// the palette
+//
+// input scripts have one change per line, `<frame> <port> <buttons>`, which holds the buttons
+// from that frame onwards. buttons is a mask (bit 0 is A, then B, Select, Start, Up, Down, Left,
+// Right) in decimal or 0x hex. everything after a # is a comment
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+
+use nes_emulator::options::MachineOptions;
+use nes_emulator::palette::Palette;
+use nes_emulator::{blargg, image, Nes, Port};
+
+struct Options {
+    rom_path: String,
+    frames: Option<u32>,
+    blargg: bool,
+    input: Option<String>,
+    screenshot: Option<String>,
+    ram: Option<String>,
+    vram: Option<String>,
+    machine: MachineOptions,
+    palette: Palette,
+}
+
+impl Options {
+    fn parse() -> anyhow::Result<Self> {
+        let mut rom_path = None;
+        let mut frames = None;
+        let mut blargg = false;
+        let mut input = None;
+        let mut screenshot = None;
+        let mut ram = None;
+        let mut vram = None;
+        let mut machine = MachineOptions::default();
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{arg} needs a value"));
+            if machine.parse_arg(&arg, &mut value)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--frames" => frames = Some(value()?.parse()?),
+                "--blargg" => blargg = true,
+                "--input" => input = Some(value()?),
+                "--screenshot" => screenshot = Some(value()?),
+                "--ram" => ram = Some(value()?),
+                "--vram" => vram = Some(value()?),
+                _ if arg.starts_with("--") => bail!("unknown option {arg}"),
+                _ => rom_path = Some(arg),
+            }
+        }
+
+        Ok(Options {
+            rom_path: rom_path.ok_or_else(|| {
+                anyhow!(
+                    "usage: nes-headless [--frames N] [--blargg] [--input FILE] \
+                     [--screenshot FILE] [--ram FILE] [--vram FILE] {} <rom>",
+                    MachineOptions::USAGE
+                )
+            })?,
+            frames,
+            blargg,
+            input,
+            screenshot,
+            ram,
+            vram,
+            palette: machine.palette()?,
+            machine,
+        })
+    }
+}
+
+struct InputEvent {
+    frame: u32,
+    port: Port,
+    buttons: u8,
+}
+
+fn parse_number(s: &str) -> anyhow::Result<u32> {
+    Ok(match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => s.parse()?,
+    })
+}
+
+fn parse_input_script(script: &str) -> anyhow::Result<Vec<InputEvent>> {
+    let mut events = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [frame, port, buttons] = fields[..] else {
+            bail!("line {}: expected `<frame> <port> <buttons>`", i + 1);
+        };
+        let event = (|| -> anyhow::Result<InputEvent> {
+            let port = Port::try_from(parse_number(port)? as usize)?;
+            Ok(InputEvent {
+                frame: parse_number(frame)?,
+                port,
+                buttons: u8::try_from(parse_number(buttons)?)?,
+            })
+        })()
+        .with_context(|| format!("line {}", i + 1))?;
+        events.push(event);
+    }
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+fn main() -> anyhow::Result<()> {
+    let options = Options::parse()?;
+
+    let rom = std::fs::read(&options.rom_path)
+        .with_context(|| format!("couldn't read {}", options.rom_path))?;
+    let mut nes = options.machine.load(&rom)?;
+
+    let events = match &options.input {
+        Some(path) => parse_input_script(
+            &std::fs::read_to_string(path).with_context(|| format!("couldn't read {path}"))?,
+        )
+        .with_context(|| format!("in {path}"))?,
+        None => vec![],
+    };
+    let mut events = events.iter().peekable();
+    let mut press_buttons = |nes: &mut Nes, frame: u32| {
+        while let Some(event) = events.next_if(|e| e.frame <= frame) {
+            nes.set_buttons(event.port, event.buttons);
+        }
+    };
+
+    let result = if options.blargg {
+        // the tests take a while, give them a minute by default
+        let max_frames = options.frames.unwrap_or(60 * 60);
+        Some(blargg::run_with(&mut nes, max_frames, press_buttons))
+    } else {
+        for frame in 0..options.frames.unwrap_or(60) {
+            press_buttons(&mut nes, frame);
+            nes.run_frame();
+        }
+        None
+    };
+
+    if let Some(path) = &options.screenshot {
+        let mut rgb = vec![0; 256 * 240 * 3];
+        options.palette.to_rgb24(nes.framebuffer(), &mut rgb);
+        let mut file = BufWriter::new(File::create(path)?);
+        if Path::new(path).extension().is_some_and(|e| e == "png") {
+            image::write_png(&mut file, 256, 240, &rgb)?;
+        } else {
+            image::write_ppm(&mut file, 256, 240, &rgb)?;
+        }
+        // dropping it would flush too, but any error from that would get lost
+        file.flush().with_context(|| format!("couldn't write {path}"))?;
+    }
+    if let Some(path) = &options.ram {
+        std::fs::write(path, nes.ram())?;
+    }
+    if let Some(path) = &options.vram {
+        std::fs::write(path, nes.vram())?;
+    }
+
+    match result {
+        Some(result) => {
+            print!("{}", result.message);
+            match result.code {
+                Some(0) => println!("passed"),
+                Some(code) => println!("failed with code {code}"),
+                None => println!("timed out"),
+            }
+            if !result.passed() {
+                std::process::exit(1);
+            }
+        }
+        None => println!("{:08x}", nes.frame_hash()),
+    }
+
+    Ok(())
+}
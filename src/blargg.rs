@@ -0,0 +1,124 @@
+// the protocol blargg's test roms use to report results through prg ram
+//
+// $6000        status: 0x80 while running, 0x81 when the reset button needs pressing, otherwise
+//              the result code (0 is a pass)
+// $6001-$6003  DE B0 61 once the rom has started using the protocol, nothing else means anything
+//              until this is there
+// $6004-       nul terminated text, the same thing the rom prints on screen
+
+use crate::Nes;
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+/// How long to wait before pressing reset when a rom asks for it, they want at least 100ms
+const RESET_DELAY_FRAMES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The signature hasn't been written yet
+    NotStarted,
+    Running,
+    NeedsReset,
+    Done(u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    /// 0 is a pass, anything else is a failure (what it means depends on the test), or None when
+    /// the rom never finished
+    pub code: Option<u8>,
+    pub message: String,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+pub fn status(nes: &Nes) -> Status {
+    let ram = nes.prg_ram();
+    // carts without prg ram can't be using the protocol
+    if ram.get(1..4) != Some(&SIGNATURE[..]) {
+        return Status::NotStarted;
+    }
+    match ram[0] {
+        0x80 => Status::Running,
+        0x81 => Status::NeedsReset,
+        code => Status::Done(code),
+    }
+}
+
+/// The text the rom has written so far
+pub fn message(nes: &Nes) -> String {
+    let text = nes.prg_ram().get(4..).unwrap_or_default();
+    let len = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+    String::from_utf8_lossy(&text[..len]).into_owned()
+}
+
+/// Runs a test rom until it finishes, pressing reset whenever it asks, giving up after
+/// `max_frames`
+pub fn run(nes: &mut Nes, max_frames: u32) -> TestResult {
+    run_with(nes, max_frames, |_, _| {})
+}
+
+/// Like `run`, but `before_frame` gets to poke at the console (to press buttons, say) before
+/// each frame, it's given the frame number
+pub fn run_with(
+    nes: &mut Nes,
+    max_frames: u32,
+    mut before_frame: impl FnMut(&mut Nes, u32),
+) -> TestResult {
+    let mut reset_in = None;
+    for frame in 0..max_frames {
+        before_frame(nes, frame);
+        nes.run_frame();
+        match status(nes) {
+            Status::NotStarted | Status::Running => {}
+            Status::NeedsReset => match reset_in {
+                None => reset_in = Some(RESET_DELAY_FRAMES),
+                Some(0) => {
+                    nes.reset();
+                    reset_in = None;
+                }
+                Some(n) => reset_in = Some(n - 1),
+            },
+            Status::Done(code) => {
+                return TestResult {
+                    code: Some(code),
+                    message: message(nes),
+                }
+            }
+        }
+    }
+    TestResult {
+        code: None,
+        message: message(nes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_prg_ram() {
+        // NES 2.0 header with a prg ram shift of 0, so no ram at $6000 at all
+        let mut rom = b"NES\x1a\x01\x01\x00\x08".to_vec();
+        rom.resize(16, 0);
+        let mut prg = vec![0; 0x4000];
+        // jmp $8000 forever
+        prg[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+        let mut nes = Nes::from_rom(&rom).unwrap();
+        assert!(nes.prg_ram().is_empty());
+
+        assert_eq!(status(&nes), Status::NotStarted);
+        assert_eq!(message(&nes), "");
+        let result = run(&mut nes, 2);
+        assert_eq!(result.code, None);
+        assert_eq!(result.message, "");
+    }
+}
@@ -1,3 +1,6 @@
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct NesController {
     // 0 - A
     // 1 - B
@@ -19,12 +22,11 @@ impl NesController {
         }
     }
 
-    pub fn set_input(&mut self, input: usize) {
-        self.bits[input] = true;
-    }
-
-    pub fn clear_input(&mut self, input: usize) {
-        self.bits[input] = false;
+    /// Sets every button at once, bit n of the mask is button n
+    pub fn set_buttons(&mut self, mask: u8) {
+        for (i, b) in self.bits.iter_mut().enumerate() {
+            *b = mask & (1 << i) != 0;
+        }
     }
 
     pub fn poll(&mut self) {
@@ -37,3 +39,20 @@ impl NesController {
         ret
     }
 }
+
+impl Savestate for NesController {
+    fn save_state(&self, w: &mut StateWriter) {
+        for b in self.bits {
+            w.bool(b);
+        }
+        w.u8(self.cur_idx as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        for b in self.bits.iter_mut() {
+            *b = r.bool()?;
+        }
+        self.cur_idx = (r.u8()? as usize).min(7);
+        Ok(())
+    }
+}
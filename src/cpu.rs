@@ -1,12 +1,17 @@
 use super::opcode::*;
+use crate::savestate::{Savestate, StateReader, StateWriter};
 
 pub trait MemoryDevice {
-    fn read_addr(&self, addr: u16) -> u8;
-    fn write_addr(&self, addr: u16, val: u8);
+    fn read_addr(&mut self, addr: u16) -> u8;
+    fn write_addr(&mut self, addr: u16, val: u8);
+
+    /// Called with how many cycles of the current instruction have gone by before the cpu
+    /// touches the bus, so anything that runs alongside the cpu can catch up to that point first
+    fn catch_up(&mut self, _cycles: usize) {}
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct Cpu<'a, M: MemoryDevice> {
+pub struct Cpu<M: MemoryDevice> {
     pub pc: u16,
     pub sp: u8,
     pub a: u8,
@@ -19,11 +24,11 @@ pub struct Cpu<'a, M: MemoryDevice> {
     pub brk: bool,
     pub overflow: bool,
     pub negative: bool,
-    memory: &'a M,
+    pub memory: M,
 }
 
-impl<'a, M: MemoryDevice> Cpu<'a, M> {
-    pub fn new(memory: &'a M) -> Self {
+impl<M: MemoryDevice> Cpu<M> {
+    pub fn new(memory: M) -> Self {
         let mut cpu = Cpu {
             pc: 0,
             sp: 0,
@@ -58,6 +63,16 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
         cpu
     }
 
+    /// What the reset button does: the registers are left alone except for the stack pointer,
+    /// which goes down by 3 like an interrupt happened, and the pc gets the reset vector
+    pub fn reset(&mut self) {
+        self.sp = self.sp.wrapping_sub(3);
+        self.interrupt = true;
+        let l = self.memory.read_addr(0xFFFC) as u16;
+        let h = self.memory.read_addr(0xFFFD) as u16;
+        self.pc = (h << 8) | l;
+    }
+
     fn get_addr(&mut self, addressing: Addressing) -> u16 {
         match addressing {
             Addressing::ZeroPage(addr) => addr as u16,
@@ -92,7 +107,10 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
             Addressing::Immediate(n) => n,
             Addressing::Relative(_) => todo!("idk"),
             Addressing::Indirect(_) => todo!("idk"),
-            addressing => self.memory.read_addr(self.get_addr(addressing)),
+            addressing => {
+                let addr = self.get_addr(addressing);
+                self.memory.read_addr(addr)
+            }
         }
     }
 
@@ -221,10 +239,25 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
     }
 
     pub fn run_instruction(&mut self) -> usize {
-        let (opcode, next_pc, cycles) = read_instruction(self.memory, self.pc);
+        let (opcode, next_pc, cycles) = read_instruction(&mut self.memory, self.pc);
         //println!("{:#4x}", self.memory.read_addr(self.pc));
         //println!("{opcode:?} {:#06x}", self.pc);
         self.pc = next_pc;
+
+        // the operand gets read or written on the last cycle, except read-modify-writes which
+        // read it two cycles before their final write
+        let rmw = matches!(
+            opcode.0,
+            Instruction::Asl
+                | Instruction::Lsr
+                | Instruction::Rol
+                | Instruction::Ror
+                | Instruction::Inc
+                | Instruction::Dec
+        ) && opcode.1 != Addressing::Accumulator;
+        let access = if rmw { cycles - 2 } else { cycles };
+        self.memory.catch_up(access - 1);
+
         match opcode.0 {
             Instruction::Adc => {
                 let n = self.read_arg(opcode.1);
@@ -249,6 +282,7 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
                     | Addressing::Absolute(_)
                     | Addressing::AbsoluteX(_) => {
                         let addr = self.get_addr(opcode.1);
+                        self.memory.catch_up(cycles - 1);
                         self.memory.write_addr(addr, tmp);
                     }
                     _ => panic!(),
@@ -302,6 +336,7 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
                 let n = n.wrapping_sub(1);
                 self.zero = n == 0;
                 self.negative = n & (1 << 7) != 0;
+                self.memory.catch_up(cycles - 1);
                 self.memory.write_addr(addr, n);
             }
             Instruction::Dex => {
@@ -324,6 +359,7 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
                 let n = n.wrapping_add(1);
                 self.zero = n == 0;
                 self.negative = n & (1 << 7) != 0;
+                self.memory.catch_up(cycles - 1);
                 self.memory.write_addr(addr, n);
             }
             Instruction::Inx => {
@@ -375,6 +411,7 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
                     | Addressing::Absolute(_)
                     | Addressing::AbsoluteX(_) => {
                         let addr = self.get_addr(opcode.1);
+                        self.memory.catch_up(cycles - 1);
                         self.memory.write_addr(addr, tmp);
                     }
                     _ => panic!(),
@@ -412,6 +449,7 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
                     | Addressing::Absolute(_)
                     | Addressing::AbsoluteX(_) => {
                         let addr = self.get_addr(opcode.1);
+                        self.memory.catch_up(cycles - 1);
                         self.memory.write_addr(addr, tmp);
                     }
                     _ => panic!(),
@@ -433,6 +471,7 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
                     | Addressing::Absolute(_)
                     | Addressing::AbsoluteX(_) => {
                         let addr = self.get_addr(opcode.1);
+                        self.memory.catch_up(cycles - 1);
                         self.memory.write_addr(addr, tmp);
                     }
                     _ => panic!(),
@@ -458,10 +497,17 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
             Instruction::Sed => self.decimal = true,
             Instruction::Sei => self.interrupt = true,
             Instruction::Sta => {
-                self.memory.write_addr(self.get_addr(opcode.1), self.a)
+                let addr = self.get_addr(opcode.1);
+                self.memory.write_addr(addr, self.a)
+            }
+            Instruction::Stx => {
+                let addr = self.get_addr(opcode.1);
+                self.memory.write_addr(addr, self.x)
+            }
+            Instruction::Sty => {
+                let addr = self.get_addr(opcode.1);
+                self.memory.write_addr(addr, self.y)
             }
-            Instruction::Stx => self.memory.write_addr(self.get_addr(opcode.1), self.x),
-            Instruction::Sty => self.memory.write_addr(self.get_addr(opcode.1), self.y),
             Instruction::Tax => {
                 self.x = self.a;
                 self.zero = self.x == 0;
@@ -489,6 +535,32 @@ impl<'a, M: MemoryDevice> Cpu<'a, M> {
                 self.negative = self.a & (1 << 7) != 0;
             }
         }
-        return cycles;
+        cycles
+    }
+}
+
+// the cpu's memory is everything else in the machine, so it gets saved along with the registers
+impl<M: MemoryDevice + Savestate> Savestate for Cpu<M> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.pc);
+        w.u8(self.sp);
+        w.u8(self.a);
+        w.u8(self.x);
+        w.u8(self.y);
+        w.u8(self.status_byte(self.brk));
+        w.bool(self.brk);
+        self.memory.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.pc = r.u16()?;
+        self.sp = r.u8()?;
+        self.a = r.u8()?;
+        self.x = r.u8()?;
+        self.y = r.u8()?;
+        let status = r.u8()?;
+        self.set_status_byte(status);
+        self.brk = r.bool()?;
+        self.memory.load_state(r)
     }
 }
@@ -0,0 +1,386 @@
+// a database of known good rom headers, for fixing dumps with the wrong mapper/mirroring/etc
+//
+// it reads the xml format of the NES 2.0 database (nes20db.xml), where each game looks like
+//
+//   <game>
+//     <!-- Some Game (USA) -->
+//     <prgrom size="32768" crc32="..." sha1="..."/>
+//     <rom size="40960" crc32="..." sha1="..."/>
+//     <pcb mapper="0" submapper="0" mirroring="V" battery="0"/>
+//     <console type="0" region="0"/>
+//     <expansion type="1"/>
+//   </game>
+//
+// <rom> is the hash of the prg and chr together, which is what games get looked up by. the
+// database built into the emulator is in gamedb.xml, a bigger one can be loaded from a file on top
+// of it
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_until, take_while1},
+    character::complete::{char, multispace0},
+    combinator::{map, value},
+    multi::many0,
+    sequence::{delimited, pair, preceded, separated_pair},
+    IResult,
+};
+
+use crate::hash;
+use crate::parser::{INesHeader, Mirroring, Rom, TvSystem};
+
+const BUILTIN: &str = include_str!("gamedb.xml");
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GameEntry {
+    /// From the comment in the entry, if there was one
+    pub name: Option<String>,
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+    // the database leaves out the rams a game doesn't have, so these are 0 unless it says
+    // otherwise
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub region: Option<TvSystem>,
+    pub expansion: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GameDb {
+    games: HashMap<u32, Vec<GameEntry>>,
+}
+
+/// The bits of xml the database uses
+#[derive(Debug, Clone)]
+enum Node<'a> {
+    Open(&'a str, Vec<(&'a str, &'a str)>),
+    /// A tag that closes itself, like <pcb ... />
+    Empty(&'a str, Vec<(&'a str, &'a str)>),
+    Close(&'a str),
+    Comment(&'a str),
+    /// <?xml ...?> and anything else that doesn't matter
+    Other,
+}
+
+fn name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || "_-:".contains(c))(input)
+}
+
+fn attribute(input: &str) -> IResult<&str, (&str, &str)> {
+    preceded(
+        multispace0,
+        separated_pair(
+            name,
+            delimited(multispace0, char('='), multispace0),
+            delimited(char('"'), take_until("\""), char('"')),
+        ),
+    )(input)
+}
+
+fn tag_node(input: &str) -> IResult<&str, Node<'_>> {
+    let (input, (name, attrs)) = preceded(char('<'), pair(name, many0(attribute)))(input)?;
+    let (input, _) = multispace0(input)?;
+    alt((
+        value(Node::Empty(name, attrs.clone()), tag("/>")),
+        value(Node::Open(name, attrs.clone()), char('>')),
+    ))(input)
+}
+
+fn node(input: &str) -> IResult<&str, Node<'_>> {
+    preceded(
+        multispace0,
+        alt((
+            map(
+                delimited(tag("<!--"), take_until("-->"), tag("-->")),
+                |text: &str| Node::Comment(text.trim()),
+            ),
+            value(
+                Node::Other,
+                delimited(tag("<?"), take_until("?>"), tag("?>")),
+            ),
+            map(
+                delimited(tag("</"), name, preceded(multispace0, char('>'))),
+                Node::Close,
+            ),
+            tag_node,
+            // text between tags, there isn't any that matters
+            value(Node::Other, take_while1(|c| c != '<')),
+        )),
+    )(input)
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut out = [0; 20];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+impl GameEntry {
+    fn set(&mut self, element: &str, attrs: &[(&str, &str)]) -> anyhow::Result<()> {
+        let get = |key: &str| attrs.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let num = |key: &str| -> anyhow::Result<Option<usize>> {
+            get(key)
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| anyhow!("bad {element} {key} \"{v}\""))
+                })
+                .transpose()
+        };
+
+        match element {
+            "rom" => {
+                let crc = get("crc32").ok_or_else(|| anyhow!("<rom> without a crc32"))?;
+                self.crc32 =
+                    u32::from_str_radix(crc, 16).map_err(|_| anyhow!("bad rom crc32 \"{crc}\""))?;
+                self.sha1 = get("sha1").and_then(parse_sha1);
+            }
+            "pcb" => {
+                self.mapper = num("mapper")?.map(|n| n as u16);
+                self.submapper = num("submapper")?.map(|n| n as u8);
+                self.mirroring = match get("mirroring") {
+                    Some("H") => Some(Mirroring::Horizontal),
+                    Some("V") => Some(Mirroring::Vertical),
+                    Some("4") => Some(Mirroring::FourScreen),
+                    // the mapper decides, so whatever the header says is as good as anything
+                    _ => None,
+                };
+                self.battery = num("battery")?.map(|n| n != 0);
+            }
+            "prgram" => self.prg_ram_size = num("size")?.unwrap_or(0),
+            "prgnvram" => self.prg_nvram_size = num("size")?.unwrap_or(0),
+            "chrram" => self.chr_ram_size = num("size")?.unwrap_or(0),
+            "chrnvram" => self.chr_nvram_size = num("size")?.unwrap_or(0),
+            "console" => {
+                self.region = match num("region")? {
+                    Some(0) => Some(TvSystem::Ntsc),
+                    Some(1) => Some(TvSystem::Pal),
+                    Some(2) => Some(TvSystem::MultiRegion),
+                    Some(3) => Some(TvSystem::Dendy),
+                    _ => None,
+                };
+            }
+            "expansion" => self.expansion = num("type")?.map(|n| n as u8),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Fixes up a header to match this entry, giving back a description of each thing that
+    /// changed
+    pub fn apply(&self, header: &mut INesHeader) -> Vec<String> {
+        let mut changes = Vec::new();
+        macro_rules! fix {
+            ($field:ident, $value:expr, $what:expr) => {
+                if let Some(value) = $value {
+                    if header.$field != value {
+                        changes.push(format!("{} {:?} -> {:?}", $what, header.$field, value));
+                        header.$field = value;
+                    }
+                }
+            };
+        }
+        fix!(map_number, self.mapper, "mapper");
+        fix!(submapper, self.submapper, "submapper");
+        fix!(mirroring, self.mirroring, "mirroring");
+        fix!(battery, self.battery, "battery");
+        fix!(prg_ram_size, Some(self.prg_ram_size), "prg ram");
+        fix!(prg_nvram_size, Some(self.prg_nvram_size), "prg nvram");
+        fix!(chr_ram_size, Some(self.chr_ram_size), "chr ram");
+        fix!(chr_nvram_size, Some(self.chr_nvram_size), "chr nvram");
+        fix!(tv_system, self.region, "region");
+        fix!(default_expansion, self.expansion, "input device");
+        changes
+    }
+}
+
+impl GameDb {
+    pub fn parse(xml: &str) -> anyhow::Result<GameDb> {
+        let mut db = GameDb::default();
+        let mut game: Option<GameEntry> = None;
+        let mut input = xml;
+
+        while !input.trim().is_empty() {
+            let (rest, node) = node(input).map_err(|_| {
+                let line = xml[..xml.len() - input.len()].lines().count();
+                anyhow!("couldn't parse the game database around line {line}")
+            })?;
+            input = rest;
+
+            match node {
+                Node::Open("game", _) => game = Some(GameEntry::default()),
+                Node::Close("game") => {
+                    let Some(game) = game.take() else {
+                        continue;
+                    };
+                    // entries without a whole-rom hash can't be looked up
+                    if game.crc32 != 0 || game.sha1.is_some() {
+                        db.games.entry(game.crc32).or_default().push(game);
+                    }
+                }
+                Node::Comment(text) => {
+                    if let Some(game) = &mut game {
+                        game.name.get_or_insert_with(|| text.to_string());
+                    }
+                }
+                Node::Empty(element, attrs) | Node::Open(element, attrs) => {
+                    if let Some(game) = &mut game {
+                        game.set(element, &attrs)?;
+                    }
+                }
+                Node::Close(_) | Node::Other => (),
+            }
+        }
+        Ok(db)
+    }
+
+    pub fn open(path: &str) -> anyhow::Result<GameDb> {
+        let xml = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("couldn't read game database {path}: {e}"))?;
+        GameDb::parse(&xml)
+    }
+
+    /// The database that comes with the emulator
+    pub fn builtin() -> &'static GameDb {
+        static DB: OnceLock<GameDb> = OnceLock::new();
+        DB.get_or_init(|| GameDb::parse(BUILTIN).expect("the built in game database is broken"))
+    }
+
+    /// Adds another database's games, the ones already in here win when both have a game
+    pub fn extend(&mut self, other: &GameDb) {
+        for (crc, games) in &other.games {
+            self.games
+                .entry(*crc)
+                .or_default()
+                .extend(games.iter().cloned());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+
+    /// Finds a game by the hash of its prg and chr. crc32 narrows it down and sha1 settles it
+    /// when the entry has one
+    pub fn lookup(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<&GameEntry> {
+        let crc = hash::crc32_update(hash::crc32(prg_rom), chr_rom);
+        let candidates = self.games.get(&crc)?;
+        if candidates.iter().all(|g| g.sha1.is_none()) {
+            return candidates.first();
+        }
+        let sha1 = hash::sha1(&[prg_rom, chr_rom].concat());
+        candidates.iter().find(|g| g.sha1.is_none_or(|s| s == sha1))
+    }
+
+    /// Looks the rom up and fixes its header if it's in here. gives back the game and what
+    /// changed, or None when it isn't known
+    pub fn correct(&self, rom: &mut Rom) -> Option<(&GameEntry, Vec<String>)> {
+        let game = self.lookup(&rom.prg_rom, &rom.chr_rom)?;
+        let changes = game.apply(&mut rom.header);
+        Some((game, changes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_game() {
+        // horizontal mapper 0 with no battery, which the database says is wrong
+        let mut data = b"NES\x1a\x01\x01\x00\x00".to_vec();
+        data.resize(16, 0);
+        let prg = vec![0xEA; 0x4000];
+        let chr = vec![0x55; 0x2000];
+        data.extend(&prg);
+        data.extend(&chr);
+
+        let crc = hash::crc32_update(hash::crc32(&prg), &chr);
+        let sha1: String = hash::sha1(&[&prg[..], &chr[..]].concat())
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect();
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db>
+  <game>
+    <!-- Test Game (USA) -->
+    <prgrom size="16384" crc32="00000000"/>
+    <rom size="24576" crc32="{crc:08X}" sha1="{sha1}"/>
+    <pcb mapper="0" submapper="0" mirroring="V" battery="1"/>
+    <prgnvram size="8192"/>
+    <console type="0" region="1"/>
+    <expansion type="1"/>
+  </game>
+</nes20db>
+"#
+        );
+        let db = GameDb::parse(&xml).unwrap();
+        assert_eq!(db.len(), 1);
+
+        let game = db.lookup(&prg, &chr).unwrap();
+        assert_eq!(game.name.as_deref(), Some("Test Game (USA)"));
+        assert_eq!(game.crc32, crc);
+        assert_eq!(game.mapper, Some(0));
+        assert_eq!(game.mirroring, Some(Mirroring::Vertical));
+        assert_eq!(game.region, Some(TvSystem::Pal));
+        assert!(db.lookup(&prg, &prg).is_none());
+
+        let mut rom = Rom::load(&data).unwrap();
+        let (_, changes) = db.correct(&mut rom).unwrap();
+        assert_eq!(
+            changes,
+            [
+                "mirroring Horizontal -> Vertical",
+                "battery false -> true",
+                "prg ram 8192 -> 0",
+                "prg nvram 0 -> 8192",
+                "region Ntsc -> Pal",
+            ]
+        );
+        assert_eq!(rom.header.mirroring, Mirroring::Vertical);
+        assert!(rom.header.battery);
+        assert_eq!(rom.header.prg_ram_size + rom.header.prg_nvram_size, 8192);
+        // already fixed, so nothing more to change
+        assert!(db.correct(&mut rom).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn builtin() {
+        let db = GameDb::builtin();
+        assert!(!db.is_empty());
+        assert!(db
+            .games
+            .values()
+            .flatten()
+            .all(|g| g.name.is_some() && g.sha1.is_some() && g.mapper.is_some()));
+    }
+
+    #[test]
+    fn extend() {
+        let game =
+            |mapper| format!(r#"<game><rom crc32="12345678"/><pcb mapper="{mapper}"/></game>"#);
+        let mut db = GameDb::parse(&game(1)).unwrap();
+        db.extend(&GameDb::parse(&game(2)).unwrap());
+        assert_eq!(db.len(), 2);
+        // the first one added wins the lookup
+        assert_eq!(db.games[&0x12345678][0].mapper, Some(1));
+    }
+}
@@ -0,0 +1,62 @@
+// writing frames out as image files, for screenshots and the headless runner
+
+use std::io::{self, Write};
+
+use crate::hash;
+
+/// Binary ppm (P6) from rgb24 pixels
+pub fn write_ppm<W: Write>(mut w: W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    write!(w, "P6\n{width} {height}\n255\n")?;
+    w.write_all(rgb)
+}
+
+/// png from rgb24 pixels. the image data is stored without any compression since that would need
+/// a whole deflate implementation, so these are about the same size as a ppm
+pub fn write_png<W: Write>(mut w: W, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    w.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bit depth, truecolour, default compression/filter, not interlaced
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut w, b"IHDR", &ihdr)?;
+
+    // every row starts with its filter type, which is always 0 (none)
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream made of stored deflate blocks
+    let mut idat = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        idat.push(blocks.peek().is_none() as u8);
+        idat.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        idat.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        idat.extend_from_slice(block);
+    }
+    idat.extend_from_slice(&adler32(&raw).to_be_bytes());
+    write_chunk(&mut w, b"IDAT", &idat)?;
+
+    write_chunk(&mut w, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    let crc = hash::crc32_update(hash::crc32(kind), data);
+    w.write_all(&crc.to_be_bytes())
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
@@ -0,0 +1,23 @@
+// the emulator itself, the sdl frontend in main.rs (and anything else) drives it through Nes
+
+pub mod battery;
+pub mod blargg;
+pub mod gamedb;
+mod controller;
+mod cpu;
+pub mod hash;
+pub mod image;
+mod nes;
+mod opcode;
+pub mod palette;
+pub mod options;
+pub mod parser;
+mod ppu;
+pub mod rewind;
+pub mod savestate;
+
+mod mapper0;
+
+use cpu::MemoryDevice;
+
+pub use nes::{ConsoleModel, Nes, Port, Region};
@@ -1,61 +1,126 @@
-// XXX DELETE THIS WHEN DONE!! XXX
-#![allow(dead_code)]
-#![feature(bigint_helper_methods)]
-
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod, Scancode};
 use sdl2::pixels::{Color, PixelFormatEnum};
 
-mod controller;
-mod cpu;
-mod opcode;
-mod parser;
-mod ppu;
+use nes_emulator::battery::BatterySave;
+use nes_emulator::options::MachineOptions;
+use nes_emulator::palette::Palette;
+use nes_emulator::{rewind, savestate, Port};
+
+fn controller_button(keycode: Keycode) -> Option<usize> {
+    Some(match keycode {
+        // a press
+        Keycode::Z => 0,
+        // b press
+        Keycode::X => 1,
+        // select press
+        Keycode::S => 2,
+        // star press
+        Keycode::Return => 3,
+        // up press
+        Keycode::Up => 4,
+        // down press
+        Keycode::Down => 5,
+        // left press
+        Keycode::Left => 6,
+        // right press
+        Keycode::Right => 7,
+        _ => return None,
+    })
+}
 
-mod mapper0;
+/// F1-F10 save to slots 1-10, holding shift loads from them instead
+fn save_slot(keycode: Keycode) -> Option<u8> {
+    Some(match keycode {
+        Keycode::F1 => 1,
+        Keycode::F2 => 2,
+        Keycode::F3 => 3,
+        Keycode::F4 => 4,
+        Keycode::F5 => 5,
+        Keycode::F6 => 6,
+        Keycode::F7 => 7,
+        Keycode::F8 => 8,
+        Keycode::F9 => 9,
+        Keycode::F10 => 10,
+        _ => return None,
+    })
+}
 
-use cpu::{Cpu, MemoryDevice};
+/// How many snapshots a frame the rewind key goes back by, tab cycles through these
+const REWIND_SPEEDS: [usize; 3] = [1, 2, 4];
 
-use std::cell::RefCell;
-#[derive(Debug, Default, PartialEq, Eq)]
-struct Memory {
-    mem: RefCell<Vec<u8>>,
+/// How often battery backed ram gets written out while playing, it's also written on exit
+const BATTERY_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Options {
+    rom_path: String,
+    rewind_interval: u32,
+    rewind_budget: usize,
+    machine: MachineOptions,
+    palette: Palette,
+    // where .sav files go, next to the rom if there isn't one
+    save_dir: Option<PathBuf>,
 }
 
-impl MemoryDevice for Memory {
-    fn read_addr(&self, addr: u16) -> u8 {
-        let val = self.mem.borrow()[addr as usize];
-        //println!("read  {addr:#06x} = {val:#04x}");
-        val
-    }
+impl Options {
+    fn parse() -> anyhow::Result<Self> {
+        let mut rom_path = None;
+        let mut rewind_interval = 2;
+        let mut rewind_budget = 64 * 1024 * 1024;
+        let mut machine = MachineOptions::default();
+        let mut save_dir = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{arg} needs a value"))
+            };
+            if machine.parse_arg(&arg, &mut value)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--rewind-interval" => rewind_interval = value()?.parse()?,
+                "--rewind-mb" => rewind_budget = value()?.parse::<usize>()? * 1024 * 1024,
+                "--save-dir" => save_dir = Some(value()?.into()),
+                _ if arg.starts_with("--") => anyhow::bail!("unknown option {arg}"),
+                _ => rom_path = Some(arg),
+            }
+        }
 
-    fn write_addr(&self, addr: u16, val: u8) {
-        //println!("wrote {addr:#06x} = {val:#04x}");
-        self.mem.borrow_mut()[addr as usize] = val;
+        Ok(Options {
+            rom_path: rom_path.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "usage: nes-emulator [--rewind-interval FRAMES] [--rewind-mb MB] {} \
+                     [--save-dir DIR] <rom>",
+                    MachineOptions::USAGE
+                )
+            })?,
+            rewind_interval,
+            rewind_budget,
+            palette: machine.palette()?,
+            machine,
+            save_dir,
+        })
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    let path = std::env::args().nth(1).unwrap();
-    let mut f = File::open(path)?;
+    let options = Options::parse()?;
+    let path = options.rom_path;
+    let mut f = File::open(&path)?;
     let mut buf = Vec::new();
     f.read_to_end(&mut buf)?;
 
-    let (_input, rom) = parser::parse_rom(&buf).unwrap();
-
-    println!("Map number {}", rom.header.map_number);
-
-    let mapper = mapper0::Mapper0::new(
-        rom.prg_rom,
-        rom.header.prg_rom_size,
-        rom.chr_rom,
-        rom.header.chr_rom_size,
-    );
-    let mut cpu = Cpu::new(&mapper);
+    let mut nes = options.machine.load(&buf)?;
+    let save_path = BatterySave::path_for(Path::new(&path), options.save_dir.as_deref());
+    let mut battery = BatterySave::open(save_path, &mut nes)?;
+    let mut last_flush = Instant::now();
 
     // copied from the docs !
     let sdl_context = sdl2::init().unwrap(); // whaaaa it's error is a string???
@@ -79,58 +144,62 @@ fn main() -> anyhow::Result<()> {
     canvas.present();
     let mut event_pump = sdl_context.event_pump().unwrap();
 
+    let mut rewind = rewind::Rewind::new(options.rewind_interval, options.rewind_budget);
+    let mut rewind_speed = 0;
+
+    let mut buttons = 0u8;
+
+    let palette = options.palette;
+    let mut rgb = vec![0; 256 * 240 * 3];
+
     'running: loop {
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } => {
                     break 'running;
                 }
-                Event::KeyUp { keycode, .. } => {
-                    if let Some(keycode) = keycode {
-                        let key = match keycode {
-                            // a press
-                            Keycode::Z => 0,
-                            // b press
-                            Keycode::X => 1,
-                            // select press
-                            Keycode::S => 2,
-                            // star press
-                            Keycode::Return => 3,
-                            // up press
-                            Keycode::Up => 4,
-                            // down press
-                            Keycode::Down => 5,
-                            // left press
-                            Keycode::Left => 6,
-                            // right press
-                            Keycode::Right => 7,
-                            _ => continue,
-                        };
-                        mapper.controller.borrow_mut().clear_input(key);
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(button) = controller_button(keycode) {
+                        buttons &= !(1 << button);
                     }
                 }
-                Event::KeyDown { keycode, .. } => {
-                    if let Some(keycode) = keycode {
-                        let key = match keycode {
-                            // a press
-                            Keycode::Z => 0,
-                            // b press
-                            Keycode::X => 1,
-                            // select press
-                            Keycode::S => 2,
-                            // star press
-                            Keycode::Return => 3,
-                            // up press
-                            Keycode::Up => 4,
-                            // down press
-                            Keycode::Down => 5,
-                            // left press
-                            Keycode::Left => 6,
-                            // right press
-                            Keycode::Right => 7,
-                            _ => continue,
-                        };
-                        mapper.controller.borrow_mut().set_input(key);
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    ..
+                } => {
+                    if let Some(slot) = save_slot(keycode) {
+                        let state_path = format!("{path}.ss{slot}");
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            let res = std::fs::read(&state_path)
+                                .map_err(anyhow::Error::from)
+                                .and_then(|data| {
+                                    savestate::load(&data, &mut nes)
+                                });
+                            match res {
+                                Ok(()) => println!("Loaded state from slot {slot}"),
+                                Err(e) => eprintln!("Couldn't load slot {slot}: {e:#}"),
+                            }
+                        } else {
+                            let data = savestate::save(&nes);
+                            match std::fs::write(&state_path, data) {
+                                Ok(()) => println!("Saved state to slot {slot}"),
+                                Err(e) => eprintln!("Couldn't save slot {slot}: {e}"),
+                            }
+                        }
+                    } else if keycode == Keycode::R
+                        && keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD)
+                    {
+                        nes.reset();
+                        println!("Reset");
+                    } else if keycode == Keycode::Tab {
+                        rewind_speed = (rewind_speed + 1) % REWIND_SPEEDS.len();
+                        println!("Rewind speed {}x", REWIND_SPEEDS[rewind_speed]);
+                    } else if let Some(button) = controller_button(keycode) {
+                        buttons |= 1 << button;
                     }
                 }
                 _ => {}
@@ -139,75 +208,62 @@ fn main() -> anyhow::Result<()> {
 
         let start_time = Instant::now();
 
-        for _ in 0..(262 * 341) {
-            let _cycles = cpu.run_instruction();
-            // this for loop is here because the ppu should be running 3x the speed of the cpu and
-            // i used to have the loop make it do that, but for some reason mario worked the best
-            // with it like this
-            // something is very wrong with this program's code!
-            // anyways let's just ignore this :))))))
-            for _ in 0..1 {
-                mapper
-                    .ppu
-                    .borrow_mut()
-                    .cycle(&mut texture, &mut cpu)
-                    .unwrap();
+        // holding backspace plays the game backwards. the state we step back to still gets run
+        // for a frame so that there's a picture to show, but that frame isn't recorded
+        let rewinding = event_pump
+            .keyboard_state()
+            .is_scancode_pressed(Scancode::Backspace);
+        if rewinding {
+            let mut state = None;
+            for _ in 0..REWIND_SPEEDS[rewind_speed] {
+                state = rewind.pop().or(state);
             }
+            // a snapshot that won't load just gets skipped, it isn't worth quitting over
+            if let Some(state) = state {
+                if let Err(e) = savestate::load(&state, &mut nes) {
+                    eprintln!("Couldn't rewind: {e:#}");
+                }
+            }
+        }
+
+        nes.set_buttons(Port::One, buttons);
+        nes.run_frame();
+        if let Some(battery) = &mut battery {
+            battery.update(&nes);
+        }
+        palette.to_rgb24(nes.framebuffer(), &mut rgb);
+        texture.update(None, &rgb, 256 * 3)?;
+
+        if !rewinding {
+            rewind.frame(|| savestate::save(&nes));
         }
 
         canvas.copy(&texture, None, None).unwrap();
 
         canvas.present();
 
-        const FRAME: Duration = Duration::from_nanos(1000000000 / 60);
+        let frame = Duration::from_secs_f64(1.0 / nes.region().frame_rate());
 
-        if let Some(left) = FRAME.checked_sub(start_time.elapsed()) {
+        if let Some(left) = frame.checked_sub(start_time.elapsed()) {
             std::thread::sleep(left);
         }
-    }
-
-    Ok(())
-
-    /*
-    let mut mem = vec![0; 0x10000];
 
-    for i in 0..0x8000 {
-        mem[0x8000 + i] = rom.prg_rom[i as usize];
+        // so a crash only loses the last few seconds of the game's save
+        if last_flush.elapsed() >= BATTERY_FLUSH_INTERVAL {
+            last_flush = Instant::now();
+            if let Some(battery) = &mut battery {
+                if let Err(e) = battery.flush(&nes) {
+                    eprintln!("{e:#}");
+                }
+            }
+        }
     }
 
-    let memory = Memory {
-        mem: RefCell::new(mem),
-    };
-
-    let mut cpu = Cpu::new(&memory);
-
-    println!("{:#x} {:#x}", rom.prg_rom[0x7FFC], rom.prg_rom[0x7FFD]);
-
-    memory.write_addr(0x2002, 0x80);
-
-    let mut counter = 0;
-
-    let time = std::time::Instant::now();
-
-    loop {
-        //println!("{:#06x} {} {} {} {}", cpu.pc, cpu.a, cpu.x, cpu.y, cpu.sp);
-        /*
-        for i in (cpu.sp as u16 +1)..=255 {
-            print!("{:#4x} ", memory.read_addr(0x0100 + i));
-        }
-        println!();
-        */
-        cpu.run_instruction();
-        counter += 1;
-        if counter % 30000 == 2890 {
-            cpu.nmi_interrupt();
-        }
-        //std::thread::sleep(std::time::Duration::from_millis(100));
-        if counter % 1000000 == 0 {
-            println!("{:09} cycles per second", counter as f64 / time.elapsed().as_secs_f64());
+    // the other ways out of the loop get the ram written when `battery` is dropped
+    if let Some(battery) = &mut battery {
+        if battery.flush(&nes)? {
+            println!("Saved {}", battery.path().display());
         }
     }
-
-    //Ok(())
-    */
+    Ok(())
 }
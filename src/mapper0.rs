@@ -1,15 +1,77 @@
-use std::cell::RefCell;
-
-use crate::ppu::Ppu;
+use crate::parser::Mirroring;
+use crate::ppu::{ChrMemory, Ppu};
 use crate::MemoryDevice;
 use crate::controller::NesController;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// The cart's 8KB of pattern tables, rom or ram if it didn't come with any chr. nrom can't bank
+/// them so the ppu sees all of it
+#[derive(Debug, Clone)]
+pub struct Chr {
+    data: Vec<u8>,
+    ram: bool,
+}
+
+impl Chr {
+    pub fn new(chr_rom: Vec<u8>, chr_rom_size: usize) -> Self {
+        let ram = chr_rom_size == 0;
+        let data = if ram {
+            vec![0; 0x2000]
+        } else {
+            chr_rom[..chr_rom_size].to_vec()
+        };
+        Chr { data, ram }
+    }
+}
+
+impl ChrMemory for Chr {
+    fn read_chr(&self, addr: u16) -> u8 {
+        self.data[addr as usize % self.data.len()]
+    }
 
+    fn write_chr(&mut self, addr: u16, val: u8) {
+        // chr rom can't be written, so only do it when there's ram there
+        if self.ram {
+            let len = self.data.len();
+            self.data[addr as usize % len] = val;
+        }
+    }
+}
+
+// chr rom comes from the rom file, only ram needs saving
+impl Savestate for Chr {
+    fn save_state(&self, w: &mut StateWriter) {
+        if self.ram {
+            w.bytes(&self.data);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        if self.ram {
+            r.bytes_into(&mut self.data)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Mapper0 {
-    memory: RefCell<Vec<u8>>,
-    pub ppu: RefCell<Ppu>,
-    pub controller: RefCell<NesController>,
+    memory: Vec<u8>,
+    // up to 8KB at $6000-$7FFF, mirrored if it's smaller and empty if the cart has none
+    prg_ram: Vec<u8>,
+    pub ppu: Ppu,
+    chr: Chr,
+    pub controllers: [NesController; 2],
     prg_rom: Vec<u8>,
     prg_rom_size: usize,
+
+    // how many cycles of the current instruction the ppu has been run for already
+    step_cycles: usize,
+    // fifths of a ppu dot left over from the last cycle, for pal
+    dot_fraction: u32,
+    frame_done: bool,
+    // an nmi the cpu saw in time to take it after the current instruction
+    nmi_polled: bool,
 }
 
 impl Mapper0 {
@@ -18,36 +80,107 @@ impl Mapper0 {
         prg_rom_size: usize,
         chr_rom: Vec<u8>,
         chr_rom_size: usize,
+        mirroring: Mirroring,
+        prg_ram_size: usize,
     ) -> Self {
         Mapper0 {
-            memory: RefCell::new(vec![0; 0x800]),
-            ppu: RefCell::new(Ppu::new(chr_rom, chr_rom_size)),
-            controller: RefCell::new(NesController::new()),
+            memory: vec![0; 0x800],
+            // nrom can't bank so anything past 8KB is unreachable anyway
+            prg_ram: vec![0; prg_ram_size.min(0x2000)],
+            ppu: Ppu::new(mirroring),
+            chr: Chr::new(chr_rom, chr_rom_size),
+            controllers: [NesController::new(), NesController::new()],
             prg_rom,
             prg_rom_size,
+            step_cycles: 0,
+            dot_fraction: 0,
+            frame_done: false,
+            nmi_polled: false,
         }
     }
+
+    /// Runs the ppu for one cpu cycle, 3 dots (3.2 on pal)
+    fn run_cycle(&mut self) {
+        let fifths = self.ppu.region().dots_per_cycle_x5() + self.dot_fraction;
+        self.dot_fraction = fifths % 5;
+        for _ in 0..fifths / 5 {
+            self.frame_done |= self.ppu.cycle(&self.chr);
+        }
+    }
+
+    /// Finishes off an instruction (or interrupt) that took `cycles` cycles, giving back
+    /// whether the ppu finished a frame during it
+    pub fn finish_step(&mut self, cycles: usize) -> bool {
+        // the cpu looks for interrupts before the last cycle, so an nmi that starts during it
+        // has to wait for the next instruction
+        self.catch_up(cycles.saturating_sub(1));
+        self.nmi_polled |= self.ppu.take_nmi();
+        self.catch_up(cycles);
+        self.step_cycles = 0;
+        std::mem::take(&mut self.frame_done)
+    }
+
+    /// Whether there's an nmi for the cpu to take before its next instruction
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_polled)
+    }
+
+    /// The ppu's whole 16KB address space, pattern tables and all
+    pub fn vram(&self) -> Vec<u8> {
+        self.ppu.vram(&self.chr)
+    }
+
+    /// The 2KB of internal ram
+    pub fn ram(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    /// Puts a 512 byte trainer at $7000-$71FF, which has to happen before the cpu fetches the
+    /// reset vector since some of them hook it
+    pub fn load_trainer(&mut self, trainer: &[u8]) {
+        // a trainer needs somewhere to live even if the header forgot about the ram
+        if self.prg_ram.len() < 0x2000 {
+            self.prg_ram = vec![0; 0x2000];
+        }
+        self.prg_ram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer);
+    }
 }
 
 impl MemoryDevice for Mapper0 {
-    fn read_addr(&self, addr: u16) -> u8 {
+    fn catch_up(&mut self, cycles: usize) {
+        while self.step_cycles < cycles {
+            self.run_cycle();
+            self.step_cycles += 1;
+        }
+    }
+
+    fn read_addr(&mut self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x1FFF => self.memory.borrow_mut()[addr as usize % 0x0800],
+            0x0000..=0x1FFF => self.memory[addr as usize % 0x0800],
             0x2000..=0x3FFF => {
                 let addr = 0x2000 + (addr - 0x2000) % 8;
-                self.ppu.borrow_mut().read_addr(addr)
-            }
-            0x4014 => {
-                self.ppu.borrow_mut().read_addr(addr)
+                self.ppu.read_addr(addr, &self.chr)
             }
             0x4016 | 0x4017 => {
-                self.controller.borrow_mut().read_input()
+                self.controllers[addr as usize - 0x4016].read_input()
             }
             0x4000..=0x4017 => {
                 //panic!("APU and or I/O :(");
                 0
             }
-            0x4020..=0xFFFF => {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()]
+            }
+            0x4020..=0x7FFF => 0,
+            0x8000..=0xFFFF => {
                 let addr = (addr as usize - 0x8000) % self.prg_rom_size;
                 self.prg_rom[addr]
             }
@@ -55,30 +188,65 @@ impl MemoryDevice for Mapper0 {
         }
     }
 
-    fn write_addr(&self, addr: u16, val: u8) {
+    fn write_addr(&mut self, addr: u16, val: u8) {
         match addr {
-            0x0000..=0x17FF => {
-                self.memory.borrow_mut()[addr as usize % 0x0800] = val;
+            0x0000..=0x1FFF => {
+                self.memory[addr as usize % 0x0800] = val;
             }
             0x2000..=0x3FFF => {
                 let addr = 0x2000 + (addr - 0x2000) % 8;
-                self.ppu.borrow_mut().write_addr(addr, val, self)
+                self.ppu.write_addr(addr, val, &mut self.chr)
             }
             0x4014 => {
-                self.ppu.borrow_mut().write_addr(addr, val, self)
+                // OAMDMA, the ppu can't see the rest of the bus so we do the reading for it
+                let page = (val as u16) << 8;
+                let data: Vec<u8> = (0..=255).map(|i| self.read_addr(page + i)).collect();
+                self.ppu.oam_dma(&data);
             }
             0x4016 => {
-                self.controller.borrow_mut().poll();
+                for controller in self.controllers.iter_mut() {
+                    controller.poll();
+                }
             }
             0x4000..=0x4017 => {
                 //panic!("APU and or I/O :(");
             }
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = val;
+            }
             0x4020..=0xFFFF => {
                 //panic!("reading to rom?!?! or not?? {addr:#06x}");
-                //println!("Wrote {val:#04x} to {addr:#06x}");
-                print!("{}", val as char);
             }
             _ => panic!("dont know how to write to {addr:#06x}"),
         }
     }
 }
+
+// nrom has no banking so the rams are the only things of ours to save
+impl Savestate for Mapper0 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.memory);
+        w.bytes(&self.prg_ram);
+        self.ppu.save_state(w);
+        self.chr.save_state(w);
+        w.u8(self.dot_fraction as u8);
+        w.bool(self.nmi_polled);
+        for controller in &self.controllers {
+            controller.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        r.bytes_into(&mut self.memory)?;
+        r.bytes_into(&mut self.prg_ram)?;
+        self.ppu.load_state(r)?;
+        self.chr.load_state(r)?;
+        self.dot_fraction = r.u8()? as u32 % 5;
+        self.nmi_polled = r.bool()?;
+        for controller in self.controllers.iter_mut() {
+            controller.load_state(r)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,398 @@
+use crate::cpu::Cpu;
+use crate::gamedb::GameDb;
+use crate::hash;
+use crate::mapper0::Mapper0;
+use crate::parser::{Rom, TvSystem};
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+/// Which console it is, they're wired up differently around reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsoleModel {
+    /// The original nes, where the reset button resets the ppu too
+    #[default]
+    FrontLoader,
+    /// The nes-101, the ppu only gets reset by turning it off and on
+    TopLoader,
+    /// Same as the top-loader as far as reset goes
+    Famicom,
+}
+
+impl std::str::FromStr for ConsoleModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "front-loader" | "nes" => Ok(ConsoleModel::FrontLoader),
+            "top-loader" | "nes-101" => Ok(ConsoleModel::TopLoader),
+            "famicom" => Ok(ConsoleModel::Famicom),
+            _ => anyhow::bail!("unknown console {s}, try front-loader, top-loader or famicom"),
+        }
+    }
+}
+
+/// One of the two controller ports on the front
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    One,
+    Two,
+}
+
+impl TryFrom<usize> for Port {
+    type Error = anyhow::Error;
+
+    /// Numbered from 0 like the registers ($4016 and $4017)
+    fn try_from(n: usize) -> anyhow::Result<Self> {
+        match n {
+            0 => Ok(Port::One),
+            1 => Ok(Port::Two),
+            _ => anyhow::bail!("there are only ports 0 and 1"),
+        }
+    }
+}
+
+/// Where the console's from, which decides its clocks and how many lines a frame has. The apu's
+/// frame counter and sample rates change with it too, that comes with the apu since there isn't
+/// one yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    /// The famiclone sold in russia, pal-ish but with ntsc speed cpu/ppu ratio and a late vblank
+    /// to make ntsc games run properly
+    Dendy,
+}
+
+impl Region {
+    /// Lines per frame, counting vblank and the pre-render line
+    pub fn scanlines(self) -> u32 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// The line vblank starts on
+    pub fn vblank_line(self) -> u32 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// Ppu dots for every cpu cycle, times 5 since pal's is 3.2
+    pub fn dots_per_cycle_x5(self) -> u32 {
+        match self {
+            Region::Ntsc | Region::Dendy => 15,
+            Region::Pal => 16,
+        }
+    }
+
+    /// Cpu cycles a second
+    pub fn cpu_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    /// Frames a second, a little over 60 for ntsc and a little over 50 for the others
+    pub fn frame_rate(self) -> f64 {
+        let mut dots = (self.scanlines() * 341) as f64;
+        if self == Region::Ntsc {
+            // every other frame is a dot short
+            dots -= 0.5;
+        }
+        self.cpu_clock() * self.dots_per_cycle_x5() as f64 / 5.0 / dots
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => anyhow::bail!("unknown region {s}, try ntsc, pal or dendy"),
+        }
+    }
+}
+
+/// The whole console. The cpu owns the bus and the bus owns everything plugged into it
+#[derive(Debug, Clone)]
+pub struct Nes {
+    cpu: Cpu<Mapper0>,
+    rom_hash: u32,
+    model: ConsoleModel,
+    region: Region,
+    // the cart keeps its prg ram when the power's off
+    battery: bool,
+}
+
+impl Nes {
+    pub(crate) fn new(mapper: Mapper0, rom_hash: u32) -> Self {
+        Nes {
+            cpu: Cpu::new(mapper),
+            rom_hash,
+            model: ConsoleModel::default(),
+            region: Region::default(),
+            battery: false,
+        }
+    }
+
+    /// Powers on a console with an iNES file plugged in, with the header fixed up by the built
+    /// in game database if the game is in there
+    pub fn from_rom(bytes: &[u8]) -> anyhow::Result<Self> {
+        Nes::with_rom(Rom::load(bytes)?)
+    }
+
+    /// Like `from_rom` but the header gets fixed up by `gamedb` instead of the built in database,
+    /// or not at all without one. also gives back warnings about everything that was wrong with
+    /// the header
+    pub fn load(bytes: &[u8], gamedb: Option<&GameDb>) -> anyhow::Result<(Self, Vec<String>)> {
+        let mut rom = Rom::load_with(bytes, gamedb)?;
+        let warnings = std::mem::take(&mut rom.warnings);
+        Ok((Nes::with_rom(rom)?, warnings))
+    }
+
+    /// Powers on a console with an already loaded rom plugged in
+    pub fn with_rom(rom: Rom) -> anyhow::Result<Self> {
+        // Rom::load already checked, but the rom could have been changed since
+        rom.check_mapper()?;
+
+        let rom_hash = hash::crc32_update(hash::crc32(&rom.prg_rom), &rom.chr_rom);
+        let mut mapper = Mapper0::new(
+            rom.prg_rom,
+            rom.header.prg_rom_size,
+            rom.chr_rom,
+            rom.header.chr_rom_size,
+            rom.header.mirroring,
+            rom.header.prg_ram_size + rom.header.prg_nvram_size,
+        );
+        if rom.header.trainer {
+            mapper.load_trainer(&rom.trainer);
+        }
+        let mut nes = Nes::new(mapper, rom_hash);
+        nes.battery = rom.header.battery;
+        nes.set_region(match rom.header.tv_system {
+            TvSystem::Ntsc | TvSystem::MultiRegion => Region::Ntsc,
+            TvSystem::Pal => Region::Pal,
+            TvSystem::Dendy => Region::Dendy,
+        });
+        Ok(nes)
+    }
+
+    /// crc32 of the rom's prg and chr, save states remember this so they can't be loaded into
+    /// another game
+    pub fn rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    pub(crate) fn bus(&self) -> &Mapper0 {
+        &self.cpu.memory
+    }
+
+    pub(crate) fn bus_mut(&mut self) -> &mut Mapper0 {
+        &mut self.cpu.memory
+    }
+
+    pub fn model(&self) -> ConsoleModel {
+        self.model
+    }
+
+    /// Changes what the reset button does from now on
+    pub fn set_model(&mut self, model: ConsoleModel) {
+        self.model = model;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Switches region, `from_rom` already picks the one the rom says it's for
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.cpu.memory.ppu.set_region(region);
+    }
+
+    /// Sends the cpu somewhere else, for test roms that get started at a different address than
+    /// their reset vector
+    pub fn set_pc(&mut self, pc: u16) {
+        self.cpu.pc = pc;
+    }
+
+    /// Presses the reset button
+    pub fn reset(&mut self) {
+        if self.model == ConsoleModel::FrontLoader {
+            self.cpu.memory.ppu.reset();
+        }
+        self.cpu.reset();
+    }
+
+    /// Runs until the ppu finishes a frame
+    pub fn run_frame(&mut self) {
+        while !self.step() {}
+    }
+
+    /// Runs one instruction, and the nmi before it if there's one waiting. the ppu gets caught
+    /// up to the cpu whenever the cpu touches the bus, so it's never behind when a register is
+    /// read or written. gives back whether a frame finished
+    fn step(&mut self) -> bool {
+        let mut frame_done = false;
+        if self.cpu.memory.take_nmi() {
+            self.cpu.nmi_interrupt();
+            frame_done |= self.cpu.memory.finish_step(7);
+        }
+        let cycles = self.cpu.run_instruction();
+        frame_done | self.cpu.memory.finish_step(cycles)
+    }
+
+    /// Sets which buttons are held on a controller port. Bit 0 is A, then B, Select, Start, Up,
+    /// Down, Left and Right
+    pub fn set_buttons(&mut self, port: Port, mask: u8) {
+        self.bus_mut().controllers[port as usize].set_buttons(mask);
+    }
+
+    /// The last finished frame, 256x240 palette indices with the emphasis bits above them. Use
+    /// a `Palette` to turn it into colours
+    pub fn framebuffer(&self) -> &[u16] {
+        self.bus().ppu.frame()
+    }
+
+    /// crc32 of the frame, which doesn't depend on the palette
+    pub fn frame_hash(&self) -> u32 {
+        let bytes: Vec<u8> = self.framebuffer().iter().flat_map(|p| p.to_le_bytes()).collect();
+        hash::crc32(&bytes)
+    }
+
+    /// The cpu's 2KB of internal ram
+    pub fn ram(&self) -> &[u8] {
+        self.bus().ram()
+    }
+
+    /// The cartridge's ram at $6000-$7FFF
+    pub fn prg_ram(&self) -> &[u8] {
+        self.bus().prg_ram()
+    }
+
+    /// The prg ram if the cart has a battery keeping it alive, which is what goes in a .sav file
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        Some(self.prg_ram()).filter(|ram| self.battery && !ram.is_empty())
+    }
+
+    /// Puts a .sav file's contents back into the battery backed ram. files made for a different
+    /// size of ram get whatever fits
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        if !self.battery {
+            return;
+        }
+        let ram = self.bus_mut().prg_ram_mut();
+        let len = ram.len().min(data.len());
+        ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    /// The ppu's whole 16KB address space
+    pub fn vram(&self) -> Vec<u8> {
+        self.bus().vram()
+    }
+
+    /// Audio made since the last frame. There's no apu yet so this is always empty
+    pub fn audio_samples(&self) -> &[f32] {
+        &[]
+    }
+}
+
+impl Savestate for Nes {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.region as u8);
+        self.cpu.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        // the state's region wins, the ppu's position in the frame only makes sense with it
+        let region = match r.u8()? {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Dendy,
+            n => anyhow::bail!("unknown region {n}"),
+        };
+        self.set_region(region);
+        self.cpu.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Mirroring;
+    use crate::MemoryDevice;
+
+    /// Reads $2002 when the ppu is `offset` dots from setting the vblank flag (0 is the read
+    /// landing just before the dot that sets it), then reads it again a few dots later. gives
+    /// back bit 7 of both reads and how many nmis happened
+    fn vblank_race(offset: i32) -> (u8, u8, u8) {
+        let mut prg = vec![0xEA; 0x4000];
+        // $8000: lda $2002, ldx $2002, jmp *
+        prg[..9].copy_from_slice(&[0xAD, 0x02, 0x20, 0xAE, 0x02, 0x20, 0x4C, 0x06, 0x80]);
+        // $9000, the nmi handler: inc $00, rti
+        prg[0x1000..0x1003].copy_from_slice(&[0xE6, 0x00, 0x40]);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0x80]);
+        let mapper = Mapper0::new(prg, 0x4000, vec![], 0, Mirroring::Horizontal, 0x2000);
+        let mut nes = Nes::new(mapper, 0);
+
+        let vblank_start = 241 * 341 + 1;
+        // lda abs reads on its 4th cycle, 9 dots in
+        let bus = nes.bus_mut();
+        bus.ppu.skip_to((vblank_start + offset - 9) as u32);
+        bus.write_addr(0x2000, 0x80);
+        for _ in 0..10 {
+            nes.step();
+        }
+        (nes.cpu.a & 0x80, nes.cpu.x & 0x80, nes.ram()[0])
+    }
+
+    #[test]
+    fn vblank_flag_and_nmi_race() {
+        // too early to see the flag, it gets set and the nmi happens after the lda
+        assert_eq!(vblank_race(-1), (0, 0x80, 1));
+        // right before it's set, the flag never gets set that frame and there's no nmi
+        assert_eq!(vblank_race(0), (0, 0, 0));
+        // the same dot or the one after, the flag is seen but the nmi gets cancelled
+        assert_eq!(vblank_race(1), (0x80, 0, 0));
+        assert_eq!(vblank_race(2), (0x80, 0, 0));
+        // late enough that it's just a normal read
+        assert_eq!(vblank_race(3), (0x80, 0, 1));
+    }
+
+    #[test]
+    fn frame_is_finished() {
+        let mut prg = vec![0xEA; 0x4000];
+        // $8000: turn nmi on until it sticks after the warm-up
+        // lda #$80, sta $2000, jmp $8000
+        prg[..8].copy_from_slice(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x00, 0x80]);
+        // $9000, the nmi handler: a new backdrop colour every frame
+        // inc $00, lda #$3f, sta $2006, lda #$00, sta $2006, lda $00, and #$0f, sta $2007, rti
+        prg[0x1000..0x1014].copy_from_slice(&[
+            0xE6, 0x00, 0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, 0xA5, 0x00,
+            0x29, 0x0F, 0x8D, 0x07, 0x20, 0x40,
+        ]);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0x80]);
+        let mapper = Mapper0::new(prg, 0x4000, vec![], 0, Mirroring::Horizontal, 0x2000);
+        let mut nes = Nes::new(mapper, 0);
+
+        for frame in 0..6 {
+            nes.run_frame();
+            // the whole frame is drawn between two nmis, none of the next frame's colour should
+            // have made it in
+            let backdrop = nes.framebuffer()[0];
+            assert!(
+                nes.framebuffer().iter().all(|&p| p == backdrop),
+                "frame {frame} has more than one colour"
+            );
+        }
+        assert!(nes.ram()[0] >= 3, "the nmi never ran");
+    }
+}
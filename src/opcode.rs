@@ -81,7 +81,7 @@ pub enum Addressing {
 }
 
 /// Reads an instruction and gives the new instruction index after that instruction
-pub fn read_instruction<M: MemoryDevice> (pmem: &M, index: u16) -> (OpCode, u16, usize) {
+pub fn read_instruction<M: MemoryDevice> (pmem: &mut M, index: u16) -> (OpCode, u16, usize) {
     use Instruction as I;
     use Addressing as A;
     match pmem.read_addr(index) {
@@ -0,0 +1,79 @@
+// the command line options every frontend has, for setting up the console and picking the colours
+// its frames get turned into. frontends offer each argument to MachineOptions::parse_arg before
+// looking at their own ones
+
+use crate::gamedb::GameDb;
+use crate::palette::{NtscParams, Palette};
+use crate::{ConsoleModel, Nes, Region};
+
+#[derive(Debug, Default)]
+pub struct MachineOptions {
+    palette: Option<String>,
+    ntsc: Option<NtscParams>,
+    pub model: ConsoleModel,
+    /// None goes with what the rom says
+    pub region: Option<Region>,
+    /// NES 2.0 xml database to fix bad headers with, on top of the built in one
+    pub gamedb: Option<String>,
+}
+
+impl MachineOptions {
+    /// These options for the frontends' usage messages
+    pub const USAGE: &'static str = "[--palette PRESET|FILE.pal] [--hue DEG] [--saturation X] \
+                                     [--contrast X] [--brightness X] \
+                                     [--model front-loader|top-loader|famicom] \
+                                     [--region auto|ntsc|pal|dendy] [--gamedb FILE]";
+
+    /// Takes `arg` if it's one of these options, getting its value from `value` if it needs one.
+    /// gives back whether it was
+    pub fn parse_arg(
+        &mut self,
+        arg: &str,
+        value: impl FnOnce() -> anyhow::Result<String>,
+    ) -> anyhow::Result<bool> {
+        match arg {
+            "--palette" => self.palette = Some(value()?),
+            "--hue" => self.ntsc.get_or_insert_default().hue = value()?.parse()?,
+            "--saturation" => self.ntsc.get_or_insert_default().saturation = value()?.parse()?,
+            "--contrast" => self.ntsc.get_or_insert_default().contrast = value()?.parse()?,
+            "--brightness" => self.ntsc.get_or_insert_default().brightness = value()?.parse()?,
+            "--model" => self.model = value()?.parse()?,
+            "--region" => {
+                self.region = match value()?.as_str() {
+                    "auto" => None,
+                    name => Some(name.parse()?),
+                }
+            }
+            "--gamedb" => self.gamedb = Some(value()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn palette(&self) -> anyhow::Result<Palette> {
+        Palette::from_option(self.palette.as_deref(), self.ntsc)
+    }
+
+    /// Powers on a console with the rom plugged in, set up the way the options say. anything
+    /// wrong with the rom's header gets printed to stderr
+    pub fn load(&self, rom: &[u8]) -> anyhow::Result<Nes> {
+        let loaded = match &self.gamedb {
+            Some(path) => {
+                let mut db = GameDb::open(path)?;
+                db.extend(GameDb::builtin());
+                Some(db)
+            }
+            None => None,
+        };
+        let gamedb = loaded.as_ref().unwrap_or(GameDb::builtin());
+        let (mut nes, warnings) = Nes::load(rom, Some(gamedb))?;
+        for warning in warnings {
+            eprintln!("{warning}");
+        }
+        nes.set_model(self.model);
+        if let Some(region) = self.region {
+            nes.set_region(region);
+        }
+        Ok(nes)
+    }
+}
@@ -0,0 +1,284 @@
+// turning the ppu's output into colours
+//
+// the ppu doesn't make rgb, it makes a palette index (6 bits) for every pixel, with the three
+// emphasis bits from ppumask above that (bits 6-8). frontends turn those into whatever pixel
+// format they want with a Palette
+//
+// a palette has a colour for all 512 of those, which is the same layout as the big kind of .pal
+// file (8 sets of 64 colours, one for each emphasis combination). the small kind only has the
+// first 64 so the rest are made up by darkening the colours that aren't being emphasised
+
+const DEFAULT_COLOURS: [[u8; 3]; 64] = [
+    [0x55, 0x55, 0x55],
+    [0x00, 0x17, 0x73],
+    [0x00, 0x07, 0x86],
+    [0x2e, 0x05, 0x78],
+    [0x59, 0x02, 0x4d],
+    [0x72, 0x00, 0x11],
+    [0x6e, 0x00, 0x00],
+    [0x4c, 0x08, 0x00],
+    [0x17, 0x1b, 0x00],
+    [0x00, 0x2a, 0x00],
+    [0x00, 0x31, 0x00],
+    [0x00, 0x2e, 0x08],
+    [0x00, 0x26, 0x45],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0xa5, 0xa5, 0xa5],
+    [0x00, 0x57, 0xc6],
+    [0x22, 0x3f, 0xe5],
+    [0x6e, 0x28, 0xd9],
+    [0xae, 0x1a, 0xa6],
+    [0xd2, 0x17, 0x59],
+    [0xd1, 0x21, 0x07],
+    [0xa7, 0x37, 0x00],
+    [0x63, 0x51, 0x00],
+    [0x18, 0x67, 0x00],
+    [0x00, 0x72, 0x00],
+    [0x00, 0x73, 0x31],
+    [0x00, 0x6a, 0x84],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0xfe, 0xff, 0xff],
+    [0x2f, 0xa8, 0xff],
+    [0x5d, 0x81, 0xff],
+    [0x9c, 0x70, 0xff],
+    [0xf7, 0x72, 0xff],
+    [0xff, 0x77, 0xbd],
+    [0xff, 0x7e, 0x75],
+    [0xff, 0x8a, 0x2b],
+    [0xcd, 0xa0, 0x00],
+    [0x81, 0xb8, 0x02],
+    [0x3d, 0xc8, 0x30],
+    [0x12, 0xcd, 0x7b],
+    [0x0d, 0xc5, 0xd0],
+    [0x3c, 0x3c, 0x3c],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+    [0xfe, 0xff, 0xff],
+    [0xa4, 0xde, 0xff],
+    [0xb1, 0xc8, 0xff],
+    [0xcc, 0xbe, 0xff],
+    [0xf4, 0xc2, 0xff],
+    [0xff, 0xc5, 0xea],
+    [0xff, 0xc7, 0xc9],
+    [0xff, 0xcd, 0xaa],
+    [0xef, 0xd6, 0x96],
+    [0xd0, 0xe0, 0x95],
+    [0xb3, 0xe7, 0xa5],
+    [0x9f, 0xea, 0xc3],
+    [0x9a, 0xe8, 0xe6],
+    [0xaf, 0xaf, 0xaf],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00],
+];
+
+// the rgb ppu (2c03) used in arcade machines, as 3 bit levels of red, green and blue. one row
+// per line of the palette
+#[rustfmt::skip]
+const RGB_PPU_LEVELS: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750, 0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+/// How much emphasis darkens the colours it isn't emphasising
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// Knobs for the generated palette
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscParams {
+    /// Degrees to rotate every colour by
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+}
+
+impl Default for NtscParams {
+    fn default() -> Self {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::from_64(&DEFAULT_COLOURS)
+    }
+}
+
+impl Palette {
+    /// Names that `open` and `preset` know about
+    pub const PRESETS: &'static [&'static str] = &["default", "rgb", "ntsc"];
+
+    pub fn preset(name: &str) -> Option<Palette> {
+        Some(match name {
+            "default" => Palette::default(),
+            "rgb" => {
+                let mut colours = [[0; 3]; 64];
+                for (c, levels) in colours.iter_mut().zip(RGB_PPU_LEVELS) {
+                    *c = [6, 3, 0].map(|shift| (((levels >> shift) & 7) * 255 / 7) as u8);
+                }
+                Palette::from_64(&colours)
+            }
+            "ntsc" => Palette::ntsc(&NtscParams::default()),
+            _ => return None,
+        })
+    }
+
+    /// A preset if there's one with that name, otherwise a .pal file
+    pub fn open(name: &str) -> anyhow::Result<Palette> {
+        if let Some(palette) = Palette::preset(name) {
+            return Ok(palette);
+        }
+        let data = std::fs::read(name).map_err(|e| {
+            anyhow::anyhow!(
+                "{name} isn't a palette file ({e}) or one of the presets ({})",
+                Palette::PRESETS.join(", ")
+            )
+        })?;
+        Palette::from_pal(&data)
+    }
+
+    /// What the frontends' --palette option does. `ntsc` is generated with the given knobs,
+    /// anything else is a preset or a file
+    pub fn from_option(name: Option<&str>, params: Option<NtscParams>) -> anyhow::Result<Palette> {
+        match (name, params) {
+            (None | Some("ntsc"), Some(params)) => Ok(Palette::ntsc(&params)),
+            (Some(name), Some(_)) => anyhow::bail!(
+                "--hue, --saturation, --contrast and --brightness only work with the ntsc \
+                 palette, not {name}"
+            ),
+            (Some(name), None) => Palette::open(name),
+            (None, None) => Ok(Palette::default()),
+        }
+    }
+
+    /// Reads a .pal file, either 64 colours (192 bytes) or 512 with the emphasis variants
+    /// (1536 bytes)
+    pub fn from_pal(data: &[u8]) -> anyhow::Result<Palette> {
+        let colours: Vec<[u8; 3]> = data.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+        match data.len() {
+            192 => Ok(Palette::from_64(&colours)),
+            1536 => Ok(Palette { colours }),
+            len => anyhow::bail!("a .pal file should be 192 or 1536 bytes, this one is {len}"),
+        }
+    }
+
+    /// Makes the emphasis variants of 64 colours up
+    pub fn from_64(base: &[[u8; 3]]) -> Palette {
+        let mut colours = Vec::with_capacity(512);
+        for emphasis in 0..8 {
+            for c in base {
+                let mut c = *c;
+                for (channel, value) in c.iter_mut().enumerate() {
+                    // a channel gets darker when one of the other two is emphasised
+                    if emphasis & !(1 << channel) != 0 {
+                        *value = (*value as f32 * EMPHASIS_ATTENUATION) as u8;
+                    }
+                }
+                colours.push(c);
+            }
+        }
+        Palette { colours }
+    }
+
+    /// Works out the colours by simulating the ppu's composite video signal and decoding it like
+    /// a tv would
+    pub fn ntsc(params: &NtscParams) -> Palette {
+        Palette {
+            colours: (0..512).map(|pixel| ntsc_colour(pixel, params)).collect(),
+        }
+    }
+
+    /// The colour of one pixel from the ppu
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colours[pixel as usize & 0x1FF]
+    }
+
+    /// 3 bytes a pixel: red, green, blue
+    pub fn to_rgb24(&self, frame: &[u16], out: &mut [u8]) {
+        for (&pixel, out) in frame.iter().zip(out.chunks_exact_mut(3)) {
+            out.copy_from_slice(&self.rgb(pixel));
+        }
+    }
+
+    /// 4 bytes a pixel: red, green, blue, alpha (always opaque)
+    pub fn to_rgba(&self, frame: &[u16], out: &mut [u8]) {
+        for (&pixel, out) in frame.iter().zip(out.chunks_exact_mut(4)) {
+            let [r, g, b] = self.rgb(pixel);
+            out.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+    }
+
+    /// One 0xAARRGGBB word a pixel
+    pub fn to_argb8888(&self, frame: &[u16], out: &mut [u32]) {
+        for (&pixel, out) in frame.iter().zip(out.iter_mut()) {
+            let [r, g, b] = self.rgb(pixel);
+            *out = 0xFF00_0000 | (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        }
+    }
+}
+
+fn ntsc_colour(pixel: u16, params: &NtscParams) -> [u8; 3] {
+    // signal voltages for each level, low and high parts of the wave
+    const LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+    const HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+    const BLACK: f32 = 0.518;
+    const WHITE: f32 = 1.962;
+
+    let colour = (pixel & 0x0F) as u32;
+    let emphasis = pixel >> 6;
+    let level = match colour {
+        // columns e and f are always black
+        14.. => 1,
+        _ => (pixel as usize >> 4) & 3,
+    };
+    // column 0 is a flat high signal (greys), columns d-f are flat low, everything else is a
+    // square wave that's high for half of the 12 phases
+    let low = if colour == 0 { HIGH[level] } else { LOW[level] };
+    let high = if colour < 13 { HIGH[level] } else { LOW[level] };
+    let in_phase = |colour: u32, phase: u32| (colour + phase) % 12 < 6;
+
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let mut signal = if in_phase(colour, phase) { high } else { low };
+        if (emphasis & 1 != 0 && in_phase(0, phase))
+            || (emphasis & 2 != 0 && in_phase(4, phase))
+            || (emphasis & 4 != 0 && in_phase(8, phase))
+        {
+            signal *= EMPHASIS_ATTENUATION;
+        }
+        let v = (signal - BLACK) / (WHITE - BLACK);
+
+        // 3.9 is where the colour burst lines up with the colours coming out right
+        let angle = std::f32::consts::PI * (phase as f32 + 3.9) / 6.0 + params.hue.to_radians();
+        y += v;
+        i += v * angle.cos();
+        q += v * angle.sin();
+    }
+    let y = y / 12.0 * params.contrast + params.brightness;
+    let i = i / 12.0 * params.saturation * params.contrast;
+    let q = q / 12.0 * params.saturation * params.contrast;
+
+    let rgb = [
+        y + 0.946882 * i + 0.623557 * q,
+        y - 0.274788 * i - 0.635691 * q,
+        y - 1.108545 * i + 1.709007 * q,
+    ];
+    // tvs have a different gamma than monitors
+    rgb.map(|c| (c.clamp(0.0, 1.0).powf(2.2 / 1.8) * 255.0).round() as u8)
+}
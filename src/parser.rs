@@ -1,9 +1,13 @@
 // parses an iNES rom file
 
+use std::fmt;
+
+use crate::gamedb::GameDb;
+
 use nom::{
     bits::bits,
     bits::complete::{bool, take},
-    bytes::complete::{tag, take as take_bytes},
+    bytes::complete::tag,
     error::Error,
     sequence::tuple,
     IResult,
@@ -11,132 +15,564 @@ use nom::{
 
 #[derive(Debug)]
 pub struct INesHeader {
-    pub map_number: u8,
+    /// Whether this is a NES 2.0 header, everything below still gets filled in for iNES ones
+    /// with the values they imply
+    pub nes2: bool,
+    pub map_number: u16,
+    pub submapper: u8,
     pub prg_rom_size: usize,
     pub chr_rom_size: usize,
-    four_screen: bool,
-    trainer: bool,
-    battery: bool,
-    mirroring: Mirroring,
-    vs: bool,
-    prg_ram_size: u8,
-    tv_system: TvSystem,
+    pub trainer: bool,
+    /// There's battery backed memory, which is the nvram sizes below for NES 2.0 and prg ram for
+    /// iNES
+    pub battery: bool,
+    pub mirroring: Mirroring,
+    pub console_type: ConsoleType,
+    // sizes in bytes of the different kinds of cartridge ram
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub tv_system: TvSystem,
+    /// Which ppu a Vs. System game wants (0 is the normal RP2C03B, see the NES 2.0 docs for
+    /// the rest)
+    pub vs_ppu: u8,
+    /// The Vs. System hardware type, only for Vs. System games
+    pub vs_hardware: u8,
+    /// Only for `ConsoleType::Extended`, the actual console type number from byte 13
+    pub extended_console: u8,
+    /// How many extra roms come after the chr rom
+    pub misc_roms: u8,
+    /// What should be plugged into the controller ports, 1 is standard controllers
+    pub default_expansion: u8,
 }
 
 #[derive(Debug)]
-pub struct INesRom {
+pub struct Rom {
     pub header: INesHeader,
     // remember that video about Arc<[u8]> you should do that
     pub trainer: Vec<u8>,
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    /// Bytes 7-15 of the header had junk in them (usually "DiskDude!") and got zeroed
+    pub cleaned_header: bool,
+    /// Everything that was wrong with the header and got fixed, either cleaned up or changed
+    /// by the game database (see `Rom::load_with`), for telling the user about
+    pub warnings: Vec<String>,
 }
 
-#[derive(Debug)]
-enum Mirroring {
+/// Everything that can be wrong with a rom file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RomError {
+    /// Doesn't start with NES\x1a, so it isn't an iNES file at all
+    BadMagic,
+    /// The file ends before all the prg rom the header promised
+    TruncatedPrg {
+        expected: usize,
+        actual: usize,
+    },
+    TruncatedChr {
+        expected: usize,
+        actual: usize,
+    },
+    UnsupportedMapper {
+        mapper: u16,
+        submapper: u8,
+    },
+    /// The header doesn't make sense even after cleaning it up
+    CorruptHeader(String),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::BadMagic => write!(f, "not an iNES rom (it doesn't start with NES\\x1a)"),
+            RomError::TruncatedPrg { expected, actual } => write!(
+                f,
+                "the rom is cut short, the header says there's {expected} bytes of prg rom but \
+                 there's only {actual}"
+            ),
+            RomError::TruncatedChr { expected, actual } => write!(
+                f,
+                "the rom is cut short, the header says there's {expected} bytes of chr rom but \
+                 there's only {actual}"
+            ),
+            RomError::UnsupportedMapper {
+                mapper,
+                submapper: 0,
+            } => {
+                write!(f, "mapper {mapper} isn't supported yet")
+            }
+            RomError::UnsupportedMapper { mapper, submapper } => {
+                write!(
+                    f,
+                    "mapper {mapper} (submapper {submapper}) isn't supported yet"
+                )
+            }
+            RomError::CorruptHeader(why) => write!(f, "corrupt header: {why}"),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mirroring {
+    #[default]
     Horizontal,
     Vertical,
+    /// The cartridge has another 2KB so every nametable is its own
+    FourScreen,
 }
 
-#[derive(Debug)]
-enum TvSystem {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TvSystem {
     Ntsc,
     Pal,
+    /// Works on either
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    /// Something else, in `extended_console`
+    Extended,
+}
+
+/// NES 2.0 rom sizes are either a plain count of units split over two bytes, or when the
+/// high nybble is all 1s, 2^exponent * (multiplier * 2 + 1) bytes
+fn rom_size(msb: u8, lsb: u8, unit: usize) -> usize {
+    if msb == 0xF {
+        let exponent = lsb >> 2;
+        let multiplier = (lsb & 3) as usize;
+        // anything this big is garbage anyway, don't overflow on it
+        1usize
+            .checked_shl(exponent as u32)
+            .and_then(|n| n.checked_mul(multiplier * 2 + 1))
+            .unwrap_or(usize::MAX)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+/// NES 2.0 ram sizes are 64 << shift, with 0 meaning there isn't any
+fn ram_size(shift: u8) -> usize {
+    match shift {
+        0 => 0,
+        shift => 64 << shift,
+    }
 }
 
 fn parse_header(input: &[u8]) -> IResult<&[u8], INesHeader> {
     let (input, _) = tag(b"NES\x1a")(input)?;
 
     let flags = tuple((
-        take::<_, u8, usize, Error<_>>(8usize), // PRG ROM size in 16KB units
-        take::<_, u8, usize, Error<_>>(8usize), // CHR ROM size in 8KB units
+        take::<_, u8, usize, Error<_>>(8usize), // PRG ROM size (LSB) in 16KB units
+        take::<_, u8, usize, Error<_>>(8usize), // CHR ROM size (LSB) in 8KB units
         take::<_, u8, usize, Error<_>>(4usize), // lower nybble of mapper number
         bool,                                   // four screen VRAM layout
         bool,                                   // 512 bit trainer
         bool,                                   // battery
         bool,                                   // mirroring: 1 for vertical, 0 for horizontal
         take::<_, u8, usize, Error<_>>(4usize), // higher nybble of mapper number
-        take::<_, u8, usize, Error<_>>(3usize), // if == 4, this rom is an NES2.0 rom, should be 0 otherwise I think
-        bool,                                   // VS Unisystem
-        take::<_, u8, usize, Error<_>>(8usize), // prg ram size, assumed 1x8kb if this is 0
-        take::<_, u8, usize, Error<_>>(7usize), // reserved (used in nes 2.0!)
-        bool,                                   // TV system (0 = NTSC, 1 = PAL)
-        take::<_, u64, usize, Error<_>>(48usize), // padding
+        take::<_, u8, usize, Error<_>>(2usize), // if == 2, this rom is an NES2.0 rom
+        take::<_, u8, usize, Error<_>>(2usize), // console type, 1 is VS Unisystem
     ));
-    bits::<_, _, Error<_>, _, _>(flags)(input).map(
-        |(
+    let (
+        input,
+        (prg_lsb, chr_lsb, map0, four_screen, trainer, battery, mirroring, map1, nes2, console),
+    ) = bits::<_, _, Error<_>, _, _>(flags)(input)?;
+    let nes2 = nes2 == 2;
+
+    let mirroring = match (four_screen, mirroring) {
+        (true, _) => Mirroring::FourScreen,
+        (false, false) => Mirroring::Horizontal,
+        (false, true) => Mirroring::Vertical,
+    };
+    let console_type = match console {
+        0 => ConsoleType::Nes,
+        1 => ConsoleType::VsSystem,
+        2 => ConsoleType::Playchoice10,
+        _ => ConsoleType::Extended,
+    };
+
+    let mut header = INesHeader {
+        nes2,
+        map_number: (map1 << 4 | map0) as u16,
+        submapper: 0,
+        prg_rom_size: prg_lsb as usize * 16384,
+        chr_rom_size: chr_lsb as usize * 8192,
+        trainer,
+        battery,
+        mirroring,
+        console_type,
+        prg_ram_size: 0,
+        prg_nvram_size: 0,
+        chr_ram_size: 0,
+        chr_nvram_size: 0,
+        tv_system: TvSystem::Ntsc,
+        vs_ppu: 0,
+        vs_hardware: 0,
+        extended_console: 0,
+        misc_roms: 0,
+        default_expansion: 1,
+    };
+
+    if nes2 {
+        let flags = tuple((
+            take::<_, u8, usize, Error<_>>(4usize), // submapper
+            take::<_, u8, usize, Error<_>>(4usize), // mapper bits 8-11
+            take::<_, u8, usize, Error<_>>(4usize), // CHR ROM size MSB
+            take::<_, u8, usize, Error<_>>(4usize), // PRG ROM size MSB
+            take::<_, u8, usize, Error<_>>(4usize), // PRG NVRAM shift
+            take::<_, u8, usize, Error<_>>(4usize), // PRG RAM shift
+            take::<_, u8, usize, Error<_>>(4usize), // CHR NVRAM shift
+            take::<_, u8, usize, Error<_>>(4usize), // CHR RAM shift
+            take::<_, u8, usize, Error<_>>(6usize), // unused
+            take::<_, u8, usize, Error<_>>(2usize), // timing: NTSC, PAL, multi, Dendy
+            take::<_, u8, usize, Error<_>>(4usize), // Vs hardware type, or unused
+            take::<_, u8, usize, Error<_>>(4usize), // Vs PPU type or extended console type
+            take::<_, u8, usize, Error<_>>(6usize), // unused
+            take::<_, u8, usize, Error<_>>(2usize), // misc ROM count
+            take::<_, u8, usize, Error<_>>(2usize), // unused
+            take::<_, u8, usize, Error<_>>(6usize), // default expansion device
+        ));
+        let (
             input,
             (
-                prg_rom_size,
-                chr_rom_size,
-                map0,
-                four_screen,
-                trainer,
-                battery,
-                mirroring,
-                map1,
-                _nes2,
-                vs,
-                mut prg_ram_size,
+                submapper,
+                map2,
+                chr_msb,
+                prg_msb,
+                prg_nvram_shift,
+                prg_ram_shift,
+                chr_nvram_shift,
+                chr_ram_shift,
                 _,
-                tv_system,
+                timing,
+                vs_hardware,
+                vs_ppu,
                 _,
+                misc_roms,
+                _,
+                default_expansion,
             ),
-        )| {
-            let map_number = map1 << 4 | map0;
-            prg_ram_size = prg_ram_size.min(1);
+        ) = bits::<_, _, Error<_>, _, _>(flags)(input)?;
 
-            let prg_rom_size = prg_rom_size as usize * 16384;
-            let chr_rom_size = chr_rom_size as usize * 8192;
+        header.map_number |= (map2 as u16) << 8;
+        header.submapper = submapper;
+        header.prg_rom_size = rom_size(prg_msb, prg_lsb, 16384);
+        header.chr_rom_size = rom_size(chr_msb, chr_lsb, 8192);
+        header.prg_ram_size = ram_size(prg_ram_shift);
+        header.prg_nvram_size = ram_size(prg_nvram_shift);
+        header.chr_ram_size = ram_size(chr_ram_shift);
+        header.chr_nvram_size = ram_size(chr_nvram_shift);
+        header.tv_system = match timing {
+            0 => TvSystem::Ntsc,
+            1 => TvSystem::Pal,
+            2 => TvSystem::MultiRegion,
+            _ => TvSystem::Dendy,
+        };
+        match console_type {
+            ConsoleType::VsSystem => {
+                header.vs_ppu = vs_ppu;
+                header.vs_hardware = vs_hardware;
+            }
+            ConsoleType::Extended => header.extended_console = vs_ppu,
+            _ => (),
+        }
+        header.misc_roms = misc_roms;
+        header.default_expansion = default_expansion;
+        Ok((input, header))
+    } else {
+        let flags = tuple((
+            take::<_, u8, usize, Error<_>>(8usize), // prg ram size, assumed 1x8kb if this is 0
+            take::<_, u8, usize, Error<_>>(7usize), // reserved
+            bool,                                   // TV system (0 = NTSC, 1 = PAL)
+            take::<_, u64, usize, Error<_>>(48usize), // padding
+        ));
+        let (input, (prg_ram_size, _, pal, _)) = bits::<_, _, Error<_>, _, _>(flags)(input)?;
 
-            let mirroring = match mirroring {
-                false => Mirroring::Horizontal,
-                true => Mirroring::Vertical,
-            };
+        // ines can't tell ram and nvram apart, the battery bit says which it is
+        let prg_ram_size = prg_ram_size.max(1) as usize * 8192;
+        if battery {
+            header.prg_nvram_size = prg_ram_size;
+        } else {
+            header.prg_ram_size = prg_ram_size;
+        }
+        // no chr rom means 8KB of chr ram instead
+        if header.chr_rom_size == 0 {
+            header.chr_ram_size = 8192;
+        }
+        header.tv_system = match pal {
+            false => TvSystem::Ntsc,
+            true => TvSystem::Pal,
+        };
+        Ok((input, header))
+    }
+}
 
-            let tv_system = match tv_system {
-                false => TvSystem::Ntsc,
-                true => TvSystem::Pal,
-            };
+impl Rom {
+    /// Loads a rom, fixing its header with the built in game database if the game is in there
+    pub fn load(data: &[u8]) -> Result<Rom, RomError> {
+        Rom::load_with(data, Some(GameDb::builtin()))
+    }
 
-            (
-                input,
-                INesHeader {
-                    map_number,
-                    prg_rom_size,
-                    chr_rom_size,
-                    four_screen,
-                    trainer,
-                    battery,
-                    mirroring,
-                    vs,
-                    prg_ram_size,
-                    tv_system,
-                },
-            )
-        },
-    )
-}
+    /// Loads a rom and then fixes its header with `gamedb` if the game is in there, or leaves
+    /// the header as it is without one
+    pub fn load_with(data: &[u8], gamedb: Option<&GameDb>) -> Result<Rom, RomError> {
+        if !data.starts_with(b"NES\x1a") {
+            return Err(RomError::BadMagic);
+        }
+        if data.len() < 16 {
+            return Err(RomError::CorruptHeader(format!(
+                "the file is only {} bytes, not even a whole header",
+                data.len()
+            )));
+        }
 
-pub fn parse_rom(input: &[u8]) -> IResult<&[u8], INesRom> {
-    let (input, header) = parse_header(input)?;
-    let (input, trainer) = match header.trainer {
-        true => take_bytes(512usize)(input).map(|(input, s)| (input, s.to_vec())),
-        false => Ok((input, vec![])),
-    }?;
-    let (input, prg_rom) =
-        take_bytes(header.prg_rom_size)(input).map(|(input, s)| (input, s.to_vec()))?;
-    let (input, chr_rom) =
-        take_bytes(header.chr_rom_size)(input).map(|(input, s)| (input, s.to_vec()))?;
-    // something about playchoice idk
-
-    Ok((
-        input,
-        INesRom {
+        // old tools used to write their name into the unused end of the header, which then
+        // reads as a mapper number and all sorts. a nes 2.0 header can have anything there but
+        // for ines it should all be 0, so if it isn't throw away everything after byte 6
+        let mut header_bytes: [u8; 16] = data[..16].try_into().unwrap();
+        let nes2 = header_bytes[7] & 0x0C == 0x08;
+        let cleaned_header = !nes2 && header_bytes[12..].iter().any(|&b| b != 0);
+        if cleaned_header {
+            header_bytes[7..].fill(0);
+        }
+
+        let (_, header) =
+            parse_header(&header_bytes).map_err(|e| RomError::CorruptHeader(e.to_string()))?;
+        if header.prg_rom_size == 0 {
+            return Err(RomError::CorruptHeader("there's no prg rom".to_string()));
+        }
+
+        let mut rest = &data[16..];
+        let mut take = |len: usize| {
+            let (taken, left) = rest.split_at(len.min(rest.len()));
+            rest = left;
+            taken.to_vec()
+        };
+        let trainer = if header.trainer { take(512) } else { vec![] };
+        if header.trainer && trainer.len() != 512 {
+            return Err(RomError::CorruptHeader(
+                "there's meant to be a trainer but the file ends first".to_string(),
+            ));
+        }
+        let prg_rom = take(header.prg_rom_size);
+        if prg_rom.len() != header.prg_rom_size {
+            return Err(RomError::TruncatedPrg {
+                expected: header.prg_rom_size,
+                actual: prg_rom.len(),
+            });
+        }
+        let chr_rom = take(header.chr_rom_size);
+        if chr_rom.len() != header.chr_rom_size {
+            return Err(RomError::TruncatedChr {
+                expected: header.chr_rom_size,
+                actual: chr_rom.len(),
+            });
+        }
+        // something about playchoice idk
+
+        let mut rom = Rom {
             header,
             trainer,
             prg_rom,
             chr_rom,
-        },
-    ))
+            cleaned_header,
+            warnings: Vec::new(),
+        };
+        if cleaned_header {
+            rom.warnings.push(
+                "warning: the end of the header had junk in it (like \"DiskDude!\"), ignored it"
+                    .to_string(),
+            );
+        }
+        if let Some((game, changes)) = gamedb.and_then(|db| db.correct(&mut rom)) {
+            let name = game.name.as_deref().unwrap_or("unnamed game");
+            for change in changes {
+                rom.warnings.push(format!("gamedb: {name}: {change}"));
+            }
+        }
+        // after the database since it might know the mapper better than the header does
+        rom.check_mapper()?;
+        Ok(rom)
+    }
+
+    /// Errors if the emulator can't run this rom's mapper, which is only nrom for now
+    pub fn check_mapper(&self) -> Result<(), RomError> {
+        if self.header.map_number != 0 {
+            return Err(RomError::UnsupportedMapper {
+                mapper: self.header.map_number,
+                submapper: self.header.submapper,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a NES 2.0 header with the given bytes 4 and 5 (size lsbs) and 9 to 11 (size msbs and ram
+    // shifts), mapper 0 otherwise
+    fn nes2_header(prg_lsb: u8, chr_lsb: u8, msb: u8, prg_ram: u8, chr_ram: u8) -> INesHeader {
+        let mut bytes = *b"NES\x1a\0\0\0\x08\0\0\0\0\0\0\0\x01";
+        bytes[4] = prg_lsb;
+        bytes[5] = chr_lsb;
+        bytes[9] = msb;
+        bytes[10] = prg_ram;
+        bytes[11] = chr_ram;
+        let (_, header) = parse_header(&bytes).unwrap();
+        assert!(header.nes2);
+        header
+    }
+
+    #[test]
+    fn rom_sizes() {
+        let header = nes2_header(2, 1, 0x00, 0, 0);
+        assert_eq!(header.prg_rom_size, 32768);
+        assert_eq!(header.chr_rom_size, 8192);
+
+        // the msb nibbles go on top of the lsbs, chr's is the high one
+        let header = nes2_header(0x00, 0x02, 0x21, 0, 0);
+        assert_eq!(header.prg_rom_size, 0x100 * 16384);
+        assert_eq!(header.chr_rom_size, 0x202 * 8192);
+    }
+
+    #[test]
+    fn rom_size_exponents() {
+        // an msb of F makes the lsb 2^exponent * (multiplier * 2 + 1)
+        let header = nes2_header(10 << 2 | 1, 4 << 2, 0xFF, 0, 0);
+        assert_eq!(header.prg_rom_size, 1024 * 3);
+        assert_eq!(header.chr_rom_size, 16);
+        assert_eq!(rom_size(0xF, 3, 16384), 7);
+        // too big to exist, but it shouldn't overflow either
+        assert_eq!(rom_size(0xF, 63 << 2 | 3, 16384), usize::MAX);
+    }
+
+    #[test]
+    fn ram_shifts() {
+        let header = nes2_header(1, 0, 0, 0x07, 0x00);
+        assert_eq!(header.prg_ram_size, 8192);
+        assert_eq!(header.prg_nvram_size, 0);
+        assert_eq!(header.chr_ram_size, 0);
+        assert_eq!(header.chr_nvram_size, 0);
+
+        let header = nes2_header(1, 0, 0, 0x70, 0x17);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 8192);
+        assert_eq!(header.chr_ram_size, 8192);
+        assert_eq!(header.chr_nvram_size, 128);
+
+        assert_eq!(ram_size(0), 0);
+        assert_eq!(ram_size(1), 128);
+        assert_eq!(ram_size(15), 64 << 15);
+    }
+
+    // an ines file with 16KB of prg and 8KB of chr after the header
+    fn ines(header: &[u8]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(16, 0);
+        data.extend(vec![0; 0x4000 + 0x2000]);
+        data
+    }
+
+    #[test]
+    fn load() {
+        let rom = Rom::load(&ines(b"NES\x1a\x01\x01\x01")).unwrap();
+        assert_eq!(rom.prg_rom.len(), 0x4000);
+        assert_eq!(rom.chr_rom.len(), 0x2000);
+        assert_eq!(rom.header.mirroring, Mirroring::Vertical);
+        assert!(rom.warnings.is_empty());
+
+        // the trainer comes before the prg
+        let mut data = b"NES\x1a\x01\x01\x04".to_vec();
+        data.resize(16, 0);
+        data.extend(vec![0x55; 512]);
+        data.extend(vec![0; 0x4000 + 0x2000]);
+        let rom = Rom::load(&data).unwrap();
+        assert_eq!(rom.trainer, vec![0x55; 512]);
+        assert_eq!(rom.prg_rom, vec![0; 0x4000]);
+    }
+
+    #[test]
+    fn load_errors() {
+        assert_eq!(Rom::load(b"").unwrap_err(), RomError::BadMagic);
+        assert_eq!(
+            Rom::load(&ines(b"NES\x00\x01\x01")).unwrap_err(),
+            RomError::BadMagic
+        );
+        assert!(matches!(
+            Rom::load(b"NES\x1a\x01\x01\x00\x00"),
+            Err(RomError::CorruptHeader(_))
+        ));
+        assert!(matches!(
+            Rom::load(&ines(b"NES\x1a\x00\x01")),
+            Err(RomError::CorruptHeader(_))
+        ));
+
+        // a trainer that isn't there
+        let mut data = b"NES\x1a\x01\x01\x04".to_vec();
+        data.resize(16 + 100, 0);
+        assert!(matches!(Rom::load(&data), Err(RomError::CorruptHeader(_))));
+
+        let mut data = ines(b"NES\x1a\x02\x01");
+        data.truncate(16 + 0x4000 + 100);
+        assert_eq!(
+            Rom::load(&data).unwrap_err(),
+            RomError::TruncatedPrg {
+                expected: 0x8000,
+                actual: 0x4000 + 100
+            }
+        );
+        let mut data = ines(b"NES\x1a\x01\x01");
+        data.truncate(16 + 0x4000 + 100);
+        assert_eq!(
+            Rom::load(&data).unwrap_err(),
+            RomError::TruncatedChr {
+                expected: 0x2000,
+                actual: 100
+            }
+        );
+
+        assert_eq!(
+            Rom::load(&ines(b"NES\x1a\x01\x01\x10")).unwrap_err(),
+            RomError::UnsupportedMapper {
+                mapper: 1,
+                submapper: 0
+            }
+        );
+        // NES 2.0 with mapper 4 submapper 1
+        assert_eq!(
+            Rom::load(&ines(b"NES\x1a\x01\x01\x40\x08\x10")).unwrap_err(),
+            RomError::UnsupportedMapper {
+                mapper: 4,
+                submapper: 1
+            }
+        );
+    }
+
+    #[test]
+    fn diskdude() {
+        // without the cleanup the 'D' in byte 7 would make this mapper 64
+        let rom = Rom::load(&ines(b"NES\x1a\x01\x01\x00DiskDude!")).unwrap();
+        assert!(rom.cleaned_header);
+        assert_eq!(rom.header.map_number, 0);
+        assert_eq!(rom.header.prg_ram_size, 8192);
+        assert_eq!(rom.warnings.len(), 1);
+        assert!(rom.warnings[0].contains("DiskDude"));
+
+        // nes 2.0 headers can have things at the end, so they're left alone
+        let rom = Rom::load(&ines(b"NES\x1a\x01\x01\x00\x08\0\0\0\0\0\0\0\x01")).unwrap();
+        assert!(!rom.cleaned_header);
+        assert_eq!(rom.header.default_expansion, 1);
+        assert!(rom.warnings.is_empty());
+    }
 }
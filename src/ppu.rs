@@ -1,75 +1,30 @@
-use crate::{Cpu, MemoryDevice};
-use sdl2::render::Texture;
-
-const PALETTE: [[u8; 3]; 64] = [
-    [0x55, 0x55, 0x55],
-    [0x00, 0x17, 0x73],
-    [0x00, 0x07, 0x86],
-    [0x2e, 0x05, 0x78],
-    [0x59, 0x02, 0x4d],
-    [0x72, 0x00, 0x11],
-    [0x6e, 0x00, 0x00],
-    [0x4c, 0x08, 0x00],
-    [0x17, 0x1b, 0x00],
-    [0x00, 0x2a, 0x00],
-    [0x00, 0x31, 0x00],
-    [0x00, 0x2e, 0x08],
-    [0x00, 0x26, 0x45],
-    [0x00, 0x00, 0x00],
-    [0x00, 0x00, 0x00],
-    [0x00, 0x00, 0x00],
-    [0xa5, 0xa5, 0xa5],
-    [0x00, 0x57, 0xc6],
-    [0x22, 0x3f, 0xe5],
-    [0x6e, 0x28, 0xd9],
-    [0xae, 0x1a, 0xa6],
-    [0xd2, 0x17, 0x59],
-    [0xd1, 0x21, 0x07],
-    [0xa7, 0x37, 0x00],
-    [0x63, 0x51, 0x00],
-    [0x18, 0x67, 0x00],
-    [0x00, 0x72, 0x00],
-    [0x00, 0x73, 0x31],
-    [0x00, 0x6a, 0x84],
-    [0x00, 0x00, 0x00],
-    [0x00, 0x00, 0x00],
-    [0x00, 0x00, 0x00],
-    [0xfe, 0xff, 0xff],
-    [0x2f, 0xa8, 0xff],
-    [0x5d, 0x81, 0xff],
-    [0x9c, 0x70, 0xff],
-    [0xf7, 0x72, 0xff],
-    [0xff, 0x77, 0xbd],
-    [0xff, 0x7e, 0x75],
-    [0xff, 0x8a, 0x2b],
-    [0xcd, 0xa0, 0x00],
-    [0x81, 0xb8, 0x02],
-    [0x3d, 0xc8, 0x30],
-    [0x12, 0xcd, 0x7b],
-    [0x0d, 0xc5, 0xd0],
-    [0x3c, 0x3c, 0x3c],
-    [0x00, 0x00, 0x00],
-    [0x00, 0x00, 0x00],
-    [0xfe, 0xff, 0xff],
-    [0xa4, 0xde, 0xff],
-    [0xb1, 0xc8, 0xff],
-    [0xcc, 0xbe, 0xff],
-    [0xf4, 0xc2, 0xff],
-    [0xff, 0xc5, 0xea],
-    [0xff, 0xc7, 0xc9],
-    [0xff, 0xcd, 0xaa],
-    [0xef, 0xd6, 0x96],
-    [0xd0, 0xe0, 0x95],
-    [0xb3, 0xe7, 0xa5],
-    [0x9f, 0xea, 0xc3],
-    [0x9a, 0xe8, 0xe6],
-    [0xaf, 0xaf, 0xaf],
-    [0x00, 0x00, 0x00],
-    [0x00, 0x00, 0x00],
-];
-
-// !!!!!!!!!!!!!!!!
+use crate::nes::Region;
+use crate::parser::Mirroring;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
 // > Writes to the following registers are ignored if earlier than ~29658 CPU clocks after reset: PPUCTRL, PPUMASK, PPUSCROLL, PPUADDR. This also means that the PPUSCROLL/PPUADDR latch will not toggle. The other registers work immediately: PPUSTATUS, OAMADDR, OAMDATA ($2004), PPUDATA, and OAMDMA ($4014).
+const WARMUP_CYCLES: u32 = 29658;
+
+/// How long the open bus latch holds a bit, it's somewhere around 600ms
+const LATCH_DECAY_FRAMES: u32 = 36;
+
+/// The cartridge's side of the ppu's address space, the pattern tables at $0000-$1FFF. they're
+/// wired to the cart and not the ppu, so mappers can bank them however they like
+pub trait ChrMemory {
+    fn read_chr(&self, addr: u16) -> u8;
+    fn write_chr(&mut self, addr: u16, val: u8);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum EvalStage {
+    /// Looking for sprites on the line and copying them into secondary oam
+    #[default]
+    Search,
+    /// Secondary oam is full, looking for a ninth sprite (with the hardware bug)
+    Overflow,
+    /// Went through all of oam, reads keep happening but nothing comes of them
+    Done,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Ppu {
@@ -80,17 +35,17 @@ pub struct Ppu {
     ppu_status: u8,
     oam_addr: u8,
 
-    // THIS IS missing 3 of the internal registers?!?! is this implemented correctly???
-    // TODO mirror 0x3000..=0x3EFF
+    // first/second write toggle shared by PPUSCROLL and PPUADDR
     w: bool,
-    scroll_x: u8,
-    scroll_y: u8,
-    cur_scroll_x: u8,
-    cur_scroll_y: u8,
 
     // should be fine right ?!?!
     oam: Vec<u8>,
-    vram: Vec<u8>,
+
+    // the ppu's own memory: the 2KB of nametable ram laid out by the mirroring, and the
+    // palettes. the pattern tables are on the cartridge, see ChrMemory
+    ciram: Vec<u8>,
+    mirroring: Mirroring,
+    palette: Vec<u8>,
 
     read_buffer: u8,
 
@@ -101,15 +56,25 @@ pub struct Ppu {
 
     // Sprite evaluation
     secondary_oam: Vec<u8>,
-    cur_sprite: u8,
-    cur_oam_rel_idx: u8,
+    eval_stage: EvalStage,
+    // bytes left to copy of the sprite that was found
+    eval_copy: u8,
+    // where the next byte goes in secondary oam
+    s_oam_addr: u8,
+    // the last thing evaluation read, which is what oamdata reads give during rendering
+    oam_latch: u8,
+    // how many sprites evaluation found for the next line, the rest of secondary oam is unused
+    sprite_count: u8,
+    // whether oam sprite 0 is in slot 0 of secondary oam, for the line being evaluated and the
+    // line being drawn
+    sprite0_next: bool,
+    sprite0_line: bool,
 
     // Rendering registers
     b_pat_reg: [u16; 2],
     b_pal_reg: [u8; 2],
     b_pal_latch: [bool; 2],
 
-    scanline_oam: Vec<u8>,
     s_pat_reg: [[u8; 2]; 8],
     s_attrs: [u8; 8],
     s_counters: [u8; 8],
@@ -119,33 +84,69 @@ pub struct Ppu {
     l_pt_low: u8,
     l_pt_high: u8,
 
-    pixel_data: Vec<u8>,
+    // palette index of every pixel, with the emphasis bits from ppumask above that. the frame
+    // gets drawn in `drawing` and swapped into `frame` when it's done, so the start of the next
+    // one can't end up in it
+    drawing: Vec<u16>,
+    frame: Vec<u16>,
+
+    // set at the start of vblank, the cpu picks it up with take_nmi
+    nmi: bool,
+    // a $2002 read right before vblank starts stops it from being flagged that frame
+    suppress_vbl: bool,
+    region: Region,
+
+    // dots left until the registers in the quote at the top start listening, see warmup_dots
+    warmup: u32,
+    // odd frames are a dot shorter when rendering
+    odd_frame: bool,
+    frame_count: u32,
+
+    // open bus, every bit fades to 0 on its own if it isn't driven for a while
+    latch: u8,
+    latch_refreshed: [u32; 8],
 }
 
 impl Ppu {
-    pub fn new(chr_rom: Vec<u8>, chr_rom_size: usize) -> Self {
-        let mut vram = vec![0; 0x4000];
-
-        for i in 0..chr_rom_size {
-            vram[i] = chr_rom[i]; // lol yikes
-        }
+    pub fn new(mirroring: Mirroring) -> Self {
+        let ciram = match mirroring {
+            Mirroring::FourScreen => vec![0; 0x1000],
+            _ => vec![0; 0x800],
+        };
 
-        Ppu {
+        let mut ppu = Ppu {
             oam: vec![0; 256],
             secondary_oam: vec![0; 32],
-            vram,
-            pixel_data: vec![0; 256 * 240 * 4],
+            ciram,
+            mirroring,
+            palette: vec![0; 32],
+            drawing: vec![0; 256 * 240],
+            frame: vec![0; 256 * 240],
             ..Default::default()
-        }
+        };
+        ppu.warmup = ppu.warmup_dots();
+        ppu
     }
 
     // i hope it's ok to be mut
-    pub fn write_addr<M: MemoryDevice>(&mut self, addr: u16, b: u8, memory: &M) {
+    pub fn write_addr(&mut self, addr: u16, b: u8, chr: &mut impl ChrMemory) {
         //println!("{:#x}", addr);
+        self.set_io_latch(b, 0xFF);
+        if self.warmup > 0 && matches!(addr, 0x2000 | 0x2001 | 0x2005 | 0x2006) {
+            return;
+        }
         match addr {
             0x2000 => {
                 // PPUCTRL
+                let was_enabled = self.nmi_interrupt();
                 self.ppu_ctrl = b;
+                if !was_enabled && self.nmi_interrupt() && self.ppu_status & 0x80 != 0 {
+                    // turning nmi on in the middle of vblank gives one straight away
+                    self.nmi = true;
+                } else if !self.nmi_interrupt() {
+                    // and turning it off right as vblank starts stops the one that was coming
+                    self.nmi = false;
+                }
                 self.t &= !(0b11 << 10);
                 self.t |= (self.ppu_ctrl as u16 & 0b11) << 10;
             }
@@ -159,25 +160,22 @@ impl Ppu {
             }
             0x2004 => {
                 // OAMDATA
-                self.oam[self.oam_addr as usize] = b;
-                self.oam_addr = self.oam_addr.wrapping_add(1);
+                self.write_oam(b);
             }
             0x2005 => {
                 // PPUSCROLL
                 match self.w {
                     false => {
-                        self.scroll_x = b;
                         self.t &= !0b11111;
-                        self.t |= (self.scroll_x as u16 >> 3) & 0b11111;
-                        self.x = self.scroll_x & 0b111;
+                        self.t |= (b as u16 >> 3) & 0b11111;
+                        self.x = b & 0b111;
                         self.w = true;
                     }
                     true => {
-                        self.scroll_y = b;
                         self.t &= !(0b11111 << 5);
-                        self.t |= ((self.scroll_y as u16 >> 3) & 0b11111) << 5;
+                        self.t |= ((b as u16 >> 3) & 0b11111) << 5;
                         self.t &= !(0b111 << 12);
-                        self.t |= (self.scroll_y as u16 & 0b111) << 12;
+                        self.t |= (b as u16 & 0b111) << 12;
                         self.w = false;
                     }
                 }
@@ -186,7 +184,8 @@ impl Ppu {
                 // PPUADDR (write twice)
                 match self.w {
                     false => {
-                        self.t &= !(0x3F << 8);
+                        // bit 14 gets cleared too, which is the top bit of fine y
+                        self.t &= !(0x7F << 8);
                         self.t |= (b as u16 & 0x3F) << 8;
                         self.w = true;
                     }
@@ -200,79 +199,227 @@ impl Ppu {
             }
             0x2007 => {
                 // PPUDATA
-                let mut addr = self.v % 0x4000;
-                if (0..0x2000).contains(&addr) {
-                    // rom (how do i generalize this to allow modifying chr-ram?!)
-                    return;
-                }
-                if (0x3F20..0x4000).contains(&addr) {
-                    addr -= addr & 0x00E0;
-                }
-                if [0x3F10, 0x3F14, 0x3F18, 0x3F1C].contains(&addr) {
-                    addr -= 0x10;
-                }
-                self.vram[addr as usize] = b;
-                self.v += match self.ppu_ctrl & (1 << 2) != 0 {
-                    false => 1,
-                    true => 32,
-                };
-                self.v %= 0x8000;
-            }
-            0x4014 => {
-                // OAMDMA
-                let off = (b as u16) << 8;
-                for i in 0..=255 {
-                    let addr = off + i;
-                    self.oam[i as usize] = memory.read_addr(addr);
-                }
+                self.mem_write(self.v, b, chr);
+                self.data_increment();
             }
-            _ => panic!("Invalid PPU address {addr:#06x}"),
+            // PPUSTATUS is read only, the write only goes as far as the latch
+            _ => (),
         }
     }
 
-    pub fn read_addr(&mut self, addr: u16) -> u8 {
-        match addr {
+    pub fn read_addr(&mut self, addr: u16, chr: &impl ChrMemory) -> u8 {
+        // the value and which of its bits the ppu actually drove, the rest come from the latch
+        let (val, driven) = match addr {
             0x2002 => {
                 // PPUSTATUS
                 self.w = false;
-                let val = self.ppu_status;
+                // self.cycle is the dot that's about to happen
+                match self.cycle.checked_sub(self.vblank_start()) {
+                    // just before the flag gets set, so it never does this frame and there's no
+                    // nmi either
+                    Some(0) => self.suppress_vbl = true,
+                    // just after, the flag is seen but the nmi still gets cancelled
+                    Some(1..=2) => self.nmi = false,
+                    _ => (),
+                }
+                let val = self.ppu_status & 0xE0 | self.io_latch() & 0x1F;
                 self.ppu_status &= 0x7F;
-                val
+                (val, 0xE0)
             }
             0x2004 => {
-                // OAMDATA
-                self.oam[self.oam_addr as usize]
+                // OAMDATA, during rendering this sees whatever sprite evaluation is doing
+                if self.rendering() {
+                    (self.oam_latch, 0xFF)
+                } else {
+                    (self.oam[self.oam_addr as usize], 0xFF)
+                }
             }
             0x2007 => {
                 // PPUDATA
-                let mut addr = self.v;
-                if [0x3F10, 0x3F14, 0x3F18, 0x3F1C].contains(&addr) {
-                    addr -= 0x10;
-                }
-                addr %= 0x4000;
-                let val = self.vram[addr as usize];
-                self.v += match self.ppu_ctrl & (1 << 2) != 0 {
-                    false => 1,
-                    true => 32,
+                let addr = self.v % 0x4000;
+                let val = if addr >= 0x3F00 {
+                    // palettes come back straight away, but the buffer still gets filled with
+                    // the nametable byte that's "under" them
+                    self.read_buffer = self.mem_read(addr - 0x1000, chr);
+                    self.mem_read(addr, chr)
+                } else {
+                    let val = self.mem_read(addr, chr);
+                    std::mem::replace(&mut self.read_buffer, val)
                 };
-                self.v %= 0x8000;
+                self.data_increment();
 
-                if (0..0x4000).contains(&addr) {
-                    let ret = self.read_buffer;
-                    self.read_buffer = val;
-                    ret
+                if addr >= 0x3F00 {
+                    // palette entries are only 6 bits, the top two are open bus
+                    let val = if self.greyscale() { val & 0x30 } else { val & 0x3F };
+                    (val | self.io_latch() & 0xC0, 0x3F)
                 } else {
-                    val
+                    (val, 0xFF)
                 }
             }
-            _ => panic!("Invalid PPU address {addr:#06x}"),
+            // write only, so you just get whatever was last on the bus
+            _ => (self.io_latch(), 0),
+        };
+        self.set_io_latch(val, driven);
+        val
+    }
+
+    /// The ppu's data bus, which holds onto the last value written or read for a while
+    fn io_latch(&mut self) -> u8 {
+        for bit in 0..8 {
+            let age = self.frame_count.wrapping_sub(self.latch_refreshed[bit]);
+            if age > LATCH_DECAY_FRAMES {
+                self.latch &= !(1 << bit);
+            }
+        }
+        self.latch
+    }
+
+    fn set_io_latch(&mut self, val: u8, mask: u8) {
+        self.latch = self.latch & !mask | val & mask;
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.latch_refreshed[bit] = self.frame_count;
+            }
         }
     }
 
+    /// Jumps to a dot with the warm-up already over
+    #[cfg(test)]
+    pub(crate) fn skip_to(&mut self, dot: u32) {
+        self.cycle = dot;
+        self.warmup = 0;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        // whatever's left of the warm-up is the same number of cpu cycles in the new region
+        self.warmup = self.warmup * region.dots_per_cycle_x5() / self.region.dots_per_cycle_x5();
+        self.region = region;
+        self.cycle %= self.frame_dots();
+    }
+
+    /// The warm-up is a number of cpu cycles, so it's more dots on pal
+    fn warmup_dots(&self) -> u32 {
+        WARMUP_CYCLES * self.region.dots_per_cycle_x5() / 5
+    }
+
+    fn frame_dots(&self) -> u32 {
+        self.region.scanlines() * 341
+    }
+
+    /// The dot the vblank flag gets set on
+    fn vblank_start(&self) -> u32 {
+        self.region.vblank_line() * 341 + 1
+    }
+
+    /// Always the last line, after vblank
+    fn pre_render_line(&self) -> u32 {
+        self.region.scanlines() - 1
+    }
+
+    /// The reset line, which only some consoles connect to the ppu. clears the registers and
+    /// starts the warm-up over, but leaves the memory alone
+    pub fn reset(&mut self) {
+        self.ppu_ctrl = 0;
+        self.ppu_mask = 0;
+        self.w = false;
+        self.t = 0;
+        self.x = 0;
+        self.read_buffer = 0;
+        self.odd_frame = false;
+        self.warmup = self.warmup_dots();
+    }
+
+    /// Moves v along after a PPUDATA access. while rendering v is busy being the scroll position,
+    /// so the access bumps it the way rendering would (coarse x and y at the same time)
+    fn data_increment(&mut self) {
+        if self.rendering() {
+            self.increment_x();
+            self.increment_y();
+        } else {
+            self.v += match self.ppu_ctrl & (1 << 2) != 0 {
+                false => 1,
+                true => 32,
+            };
+            self.v %= 0x8000;
+        }
+    }
+
+    /// Next tile across, into the next nametable at the edge
+    fn increment_x(&mut self) {
+        if self.v % 32 == 31 {
+            self.v &= !0x1F;
+            self.v ^= 0x400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    /// Next line down, from fine y into coarse y and then the next nametable
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+        } else {
+            self.v &= !0x7000;
+            let mut y = (self.v & (0b11111 << 5)) >> 5;
+            if y == 29 {
+                y = 0;
+                self.v ^= 0x800;
+            } else if y == 31 {
+                y = 0;
+            } else {
+                y += 1;
+            }
+            self.v = (self.v & !(0b11111 << 5)) | (y << 5);
+        }
+    }
+
+    /// A write to OAMDATA
+    fn write_oam(&mut self, b: u8) {
+        if self.rendering() {
+            // doesn't write anything, just bumps the sprite part of the address
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+            return;
+        }
+        // attribute bytes don't have bits 2-4
+        let b = if self.oam_addr & 3 == 2 { b & 0xE3 } else { b };
+        self.oam[self.oam_addr as usize] = b;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    /// OAMDMA ($4014), gets given the page of cpu memory that was copied
+    pub fn oam_dma(&mut self, data: &[u8]) {
+        // goes through oamdata so it starts at oamaddr
+        for &b in data {
+            self.set_io_latch(b, 0xFF);
+            self.write_oam(b);
+        }
+    }
+
+    /// Whether vblank has asked the cpu for an nmi since the last time this was called
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi)
+    }
+
     pub fn nmi_interrupt(&self) -> bool {
         self.ppu_ctrl & (1 << 7) != 0
     }
 
+    fn greyscale(&self) -> bool {
+        self.ppu_mask & 1 != 0
+    }
+
+    fn background_left(&self) -> bool {
+        self.ppu_mask & (1 << 1) != 0
+    }
+
+    fn sprites_left(&self) -> bool {
+        self.ppu_mask & (1 << 2) != 0
+    }
+
     fn background_enabled(&self) -> bool {
         self.ppu_mask & (1 << 3) != 0
     }
@@ -281,158 +428,276 @@ impl Ppu {
         self.ppu_mask & (1 << 4) != 0
     }
 
+    fn rendering_enabled(&self) -> bool {
+        self.background_enabled() || self.sprites_enabled()
+    }
+
+    /// Whether the ppu is busy drawing (or getting ready to draw) a line right now
+    fn rendering(&self) -> bool {
+        let row = self.cycle / 341;
+        self.rendering_enabled() && (row < 240 || row == self.pre_render_line())
+    }
+
     fn sprites_8x16(&self) -> bool {
         self.ppu_ctrl & (1 << 5) != 0
     }
 
-    fn vertical_mirror(&self) -> bool {
-        // idk??!?!
-        true
+    /// Where a nametable address ($2000-$3EFF) ends up in ciram
+    fn ciram_addr(&self, addr: u16) -> usize {
+        let table = (addr as usize >> 10) & 3;
+        let bank = match self.mirroring {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 1,
+            Mirroring::FourScreen => table,
+        };
+        bank * 0x400 + (addr as usize & 0x3FF)
+    }
+
+    /// $3F10/$3F14/$3F18/$3F1C are the same as the background ones, the rest of $3F00-$3FFF
+    /// repeats every 32 bytes
+    fn palette_addr(addr: u16) -> usize {
+        let addr = addr as usize & 0x1F;
+        if addr & 0x13 == 0x10 {
+            addr & 0x0F
+        } else {
+            addr
+        }
+    }
+
+    fn mem_read(&self, addr: u16, chr: &impl ChrMemory) -> u8 {
+        let addr = addr % 0x4000;
+        match addr {
+            0x0000..=0x1FFF => chr.read_chr(addr),
+            0x2000..=0x3EFF => self.ciram[self.ciram_addr(addr)],
+            _ => self.palette[Self::palette_addr(addr)],
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, val: u8, chr: &mut impl ChrMemory) {
+        let addr = addr % 0x4000;
+        match addr {
+            0x0000..=0x1FFF => chr.write_chr(addr, val),
+            0x2000..=0x3EFF => {
+                let addr = self.ciram_addr(addr);
+                self.ciram[addr] = val;
+            }
+            _ => self.palette[Self::palette_addr(addr)] = val,
+        }
     }
 
     fn read_nametable(&self) -> u8 {
         //println!("{:#x}", self.v as usize & 0xFFF);
-        self.vram[0x2000 | (self.v as usize & 0xFFF)]
+        self.ciram[self.ciram_addr(0x2000 | (self.v & 0xFFF))]
     }
 
+    /// The palette for the tile v is on, out of the attribute byte that covers 4x4 tiles
     fn read_attr(&self) -> u8 {
-        self.vram[0x23c0
-            | (self.v as usize & 0xC00)
-            | ((self.v as usize >> 4) & 0x38)
-            | ((self.v as usize >> 2) & 0x7)]
-    }
-
-    fn read_pt(&self, row: u32, nt: u8, high: bool, sprite_y: Option<u8>, tile_idx: u8) -> u8 {
-        let base_addr = if let Some(y) = sprite_y {
-            if self.sprites_8x16() {
-                // this is wrong TODO
-                if row - y as u32 >= 8 {
-                    0x1000 + tile_idx as usize >> 1
-                } else {
-                    0 + tile_idx as usize >> 1
-                }
-            } else {
-                //println!("{}", (self.ppu_ctrl as usize & 0x8) << 9);
-                ((self.ppu_ctrl as usize & 0x8) << 9) + tile_idx as usize * 16
-            }
+        let attr = self.ciram[self.ciram_addr(
+            0x23c0 | (self.v & 0xC00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x7),
+        )];
+        // bit 1 of coarse x and coarse y pick the quadrant
+        let shift = ((self.v >> 4) & 4) | (self.v & 2);
+        (attr >> shift) & 3
+    }
+
+    fn read_pt(&self, nt: u8, high: bool, chr: &impl ChrMemory) -> u8 {
+        let base_addr = ((self.ppu_ctrl as u16 & 0x10) << 8) + ((nt as u16) << 4);
+        let fine_y = (self.v >> 12) & 7;
+        chr.read_chr(base_addr + high as u16 * 8 + fine_y)
+    }
+
+    fn sprite_height(&self) -> u32 {
+        if self.sprites_8x16() {
+            16
         } else {
-            ((self.ppu_ctrl as usize & 0x10) << 8) + ((nt as usize) << 4)
-        };
+            8
+        }
+    }
 
-        let y_off = if let Some(y) = sprite_y {
-            if y == 0xFF {
-                0 // uhhhhh
-            } else {
-                (row.wrapping_sub(y as u32)) as usize % 8
-            }
+    /// `line` is the row inside the sprite, from 0 to the sprite height
+    fn read_sprite_pt(
+        &self,
+        line: u32,
+        high: bool,
+        tile_idx: u8,
+        attr: u8,
+        chr: &impl ChrMemory,
+    ) -> u8 {
+        let height = self.sprite_height();
+        // vertical flip goes over the whole sprite, so 8x16 sprites swap their halves too
+        let line = if attr & (1 << 7) != 0 {
+            height - 1 - line % height
+        } else {
+            line % height
+        } as usize;
+
+        let base_addr = if self.sprites_8x16() {
+            // 8x16 sprites ignore ppuctrl, bit 0 of the tile picks the pattern table and the
+            // top half is the even tile with the bottom half after it
+            let bank = (tile_idx as usize & 1) * 0x1000;
+            let tile = (tile_idx as usize & 0xFE) + line / 8;
+            bank + tile * 16
         } else {
-            row as usize % 8
+            ((self.ppu_ctrl as usize & 0x8) << 9) + tile_idx as usize * 16
         };
 
-        self.vram[base_addr + high as usize * 8 + y_off]
+        chr.read_chr((base_addr + high as usize * 8 + line % 8) as u16)
     }
 
-    fn render_cycle(&mut self, row: u32, cycle: u32) {
+    /// One dot of sprite evaluation for the line after `row`. odd dots read from oam and even ones
+    /// write to secondary oam, with oamaddr being the pointer into oam like on the real thing
+    fn evaluate_sprites(&mut self, row: u32, cycle: u32) {
+        match cycle {
+            1..=64 => {
+                // clearing secondary oam, oamdata reads see the $FF being written
+                self.oam_latch = 0xFF;
+                if cycle.is_multiple_of(2) {
+                    self.secondary_oam[(cycle as usize - 1) / 2] = 0xFF;
+                }
+            }
+            65..=256 => {
+                if cycle == 65 {
+                    self.eval_stage = EvalStage::Search;
+                    self.eval_copy = 0;
+                    self.s_oam_addr = 0;
+                    self.sprite0_next = false;
+                }
+                if cycle % 2 == 1 {
+                    self.oam_latch = self.oam[self.oam_addr as usize];
+                } else {
+                    self.evaluate_byte(row, cycle);
+                }
+                if cycle == 256 {
+                    self.sprite_count = self.s_oam_addr.div_ceil(4).min(8);
+                }
+            }
+            257..=320 => {
+                self.oam_addr = 0;
+                let i = (cycle as usize - 257) / 8;
+                let byte = ((cycle as usize - 257) % 8).min(3);
+                self.oam_latch = self.secondary_oam[i * 4 + byte];
+            }
+            _ => self.oam_latch = self.secondary_oam[0],
+        }
+    }
+
+    fn evaluate_byte(&mut self, row: u32, cycle: u32) {
+        let val = self.oam_latch;
+        // sprites are drawn one line below their y, so a sprite at y is on this line if row is in
+        // y..y+height. done in u32 so sprites near the bottom don't wrap around to the top
+        let on_line = row.wrapping_sub(val as u32) < self.sprite_height();
+        let old_addr = self.oam_addr;
+
+        match self.eval_stage {
+            EvalStage::Search => {
+                if let Some(slot) = self.secondary_oam.get_mut(self.s_oam_addr as usize) {
+                    *slot = val;
+                }
+                if self.eval_copy > 0 || on_line {
+                    if self.eval_copy == 0 {
+                        self.eval_copy = 4;
+                        if cycle == 66 {
+                            self.sprite0_next = true;
+                        }
+                    }
+                    self.eval_copy -= 1;
+                    self.s_oam_addr += 1;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                } else {
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                }
+
+                if self.oam_addr < old_addr {
+                    // been through all 64
+                    self.eval_stage = EvalStage::Done;
+                } else if self.eval_copy == 0 && self.s_oam_addr == 32 {
+                    self.eval_stage = EvalStage::Overflow;
+                }
+            }
+            EvalStage::Overflow => {
+                if self.eval_copy > 0 {
+                    // reading the rest of the ninth sprite
+                    self.eval_copy -= 1;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                    if self.eval_copy == 0 {
+                        self.eval_stage = EvalStage::Done;
+                    }
+                } else if on_line {
+                    self.ppu_status |= 1 << 5;
+                    self.eval_copy = 3;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                } else {
+                    // the famous bug: moving on to the next sprite also moves to the next byte
+                    // in it (without carrying), so it ends up checking tiles and attributes and
+                    // x positions as if they were y
+                    let n = self.oam_addr.wrapping_add(4) & 0xFC;
+                    let m = self.oam_addr.wrapping_add(1) & 3;
+                    self.oam_addr = n | m;
+                    if n < old_addr & 0xFC {
+                        self.eval_stage = EvalStage::Done;
+                    }
+                }
+            }
+            EvalStage::Done => {
+                self.oam_addr = self.oam_addr.wrapping_add(4) & 0xFC;
+            }
+        }
+    }
+
+    fn render_cycle(&mut self, row: u32, cycle: u32, chr: &impl ChrMemory) {
         let bg_palettes = [0, 1, 2, 3].map(|i| {
-            let c0 = self.vram[0x3F00];
-            let c1 = self.vram[0x3F00 + 4 * i + 1];
-            let c2 = self.vram[0x3F00 + 4 * i + 2];
-            let c3 = self.vram[0x3F00 + 4 * i + 3];
+            let c0 = self.palette[0];
+            let c1 = self.palette[4 * i + 1];
+            let c2 = self.palette[4 * i + 2];
+            let c3 = self.palette[4 * i + 3];
             [c0, c1, c2, c3]
         });
 
         let sprite_palettes = [0, 1, 2, 3].map(|i| {
-            let c0 = self.vram[0x3F00];
-            let c1 = self.vram[0x3F10 + 4 * i + 1];
-            let c2 = self.vram[0x3F10 + 4 * i + 2];
-            let c3 = self.vram[0x3F10 + 4 * i + 3];
+            let c0 = self.palette[0];
+            let c1 = self.palette[0x10 + 4 * i + 1];
+            let c2 = self.palette[0x10 + 4 * i + 2];
+            let c3 = self.palette[0x10 + 4 * i + 3];
             [c0, c1, c2, c3]
         });
 
         // clear status bits
-        if row == 261 && cycle == 1 {
+        let pre_render = row == self.pre_render_line();
+        if pre_render && cycle == 1 {
             self.ppu_status &= !(0b11100000);
         }
 
-        // sprite evaluation for the next scan line
-        if (1..=64).contains(&cycle) {
-            // Initialize the secondary_oam to 0xFF
-            // On the NES, each memory access took 2 cycles, so we'll just only write on the second
-            // cycle
-            if (cycle - 1) % 2 == 1 {
-                self.secondary_oam[(cycle as usize - 1) / 2] = 0xFF;
-            }
-        }
-        if cycle == 256 {
-            // bumildhgbum this looks like such a pain to implement correctly to the cycle so im
-            // just going to do everything in the last cycle
-            // This copies the sprites on the next scanline into the secondary oam
-            let sprite_height = match self.ppu_ctrl & (1 << 5) != 0 {
-                false => 8,
-                true => 16,
-            };
-            let mut s_oam_idx = 0;
-            let mut final_idx = 0;
-            for (sprite_idx, sprite) in self.oam.chunks(4).enumerate() {
-                let y_upper = sprite[0];
-                let y_lower = y_upper.saturating_add(sprite_height);
-                final_idx = sprite_idx;
-                if (y_upper..y_lower).contains(&(row as u8)) {
-                    for (i, &val) in sprite.iter().enumerate() {
-                        self.secondary_oam[s_oam_idx * 4 + i] = val;
-                    }
-                    s_oam_idx += 1;
-                    if s_oam_idx == 8 {
-                        break;
-                    }
-                }
+        if self.rendering_enabled() {
+            if pre_render && cycle == 1 && self.oam_addr >= 8 {
+                // starting rendering with oamaddr not at the start corrupts the first 8 bytes of
+                // oam with the row it's pointing at
+                let base = (self.oam_addr & 0xF8) as usize;
+                self.oam.copy_within(base..base + 8, 0);
             }
-            // check for sprite overflow, with a bug included!
-            // the bug comes from somehow incrementing the relative offset every time a byte is
-            // read
-            // idk if this offset overflows at 4 though, im just going to assume it does
-            // because then i dont have to check for out of bounds stuff
-            let mut overflow_bug = 0;
-            for i in final_idx..64 {
-                let y_upper = self.oam[i * 4 + overflow_bug];
-                let y_lower = y_upper.saturating_add(sprite_height);
-                if (y_upper..y_lower).contains(&(row as u8)) {
-                    self.ppu_status |= 1 << 5;
-                    break;
-                }
-                overflow_bug += 1;
-                overflow_bug %= 4;
+            if row < 240 {
+                self.evaluate_sprites(row, cycle);
+            } else if (257..=320).contains(&cycle) {
+                self.oam_addr = 0;
             }
-
-            //println!("{} {} {}", self.scroll_x, self.fine_x, self.scroll_y);
+        }
+        if cycle == 256 && pre_render {
+            // no evaluation on the pre-render line, so nothing gets drawn on line 0
+            self.sprite_count = 0;
+            self.sprite0_next = false;
         }
 
         // move the y scroll coordinate down
-        if cycle == 256 {
-            if self.v & 0x7000 != 0x7000 {
-                //println!("7 {:#x}", self.v & 0x7000);
-                self.v += 0x1000;
-            } else {
-                self.v &= !0x7000;
-                let mut y = (self.v & (0b11111 << 5)) >> 5;
-                if y == 29 {
-                    y = 0;
-                    self.v ^= 0x800;
-                } else if y == 31 {
-                    y = 0;
-                } else {
-                    y += 1;
-                }
-                self.v = (self.v & !(0b11111 << 5)) | (y << 5);
-            }
+        // (v is only touched while rendering is on, otherwise it's left for PPUADDR)
+        if cycle == 256 && self.rendering_enabled() {
+            self.increment_y();
         }
         // copy the horizontal scroll from t to v
-        if cycle == 257 {
+        if cycle == 257 && self.rendering_enabled() {
             let mask = 0b11111 | 0b1 << 10;
             self.v &= !mask;
             self.v |= self.t & mask;
         }
-        if (280..=304).contains(&cycle) && row == 261 && self.background_enabled() {
+        if (280..=304).contains(&cycle) && pre_render && self.rendering_enabled() {
             let mask = 0b1111 << 11 | 0b11111 << 5;
             self.v &= !mask;
             self.v |= self.t & mask;
@@ -442,24 +707,18 @@ impl Ppu {
         if cycle == 0 {
             // do nothing lol
         } else if (1..=256).contains(&cycle) {
-            let tile = ((cycle - 1) / 8 + 2) % 32;
             let rel_cycle = (cycle - 1) % 8;
             match rel_cycle {
                 1 => self.l_nametable = self.read_nametable(),
                 3 => self.l_attr = self.read_attr(),
-                5 => self.l_pt_low = self.read_pt(row, self.l_nametable, false, None, 0),
-                7 => self.l_pt_high = self.read_pt(row, self.l_nametable, true, None, 0),
+                5 => self.l_pt_low = self.read_pt(self.l_nametable, false, chr),
+                7 => self.l_pt_high = self.read_pt(self.l_nametable, true, chr),
                 _ => (),
             }
 
             // increment v horizontal scroll
-            if rel_cycle % 8 == 7 {
-                if self.v % 32 == 31 {
-                    self.v &= !0x1F;
-                    self.v ^= 0x400;
-                } else {
-                    self.v += 1;
-                }
+            if rel_cycle % 8 == 7 && self.rendering_enabled() {
+                self.increment_x();
             }
 
             /*
@@ -472,7 +731,7 @@ impl Ppu {
                     self.l_attr,
                     self.l_nametable,
                     ((self.ppu_ctrl as usize & 0x10) << 8) + ((self.l_nametable as usize) << 4),
-                    self.vram[0x1240 + 1],
+                    self.mem_read(0x1240 + 1),
                     self.v,
                 );
             }
@@ -492,31 +751,37 @@ impl Ppu {
                 self.b_pat_reg[0] >>= 1;
                 self.b_pat_reg[1] >>= 1;
 
-                let bg_pixel = pixel;
-                let bg_col = if self.background_enabled() {
-                    bg_palettes[palette as usize][pixel as usize] as usize
+                let x = cycle as usize - 1;
+                let y = row as usize;
+
+                // the left 8 pixels can be hidden separately for each layer, hidden pixels count
+                // as transparent
+                let bg_pixel = if self.background_enabled() && (x >= 8 || self.background_left()) {
+                    pixel
                 } else {
                     0
                 };
-                //assert!(bg_col < 64);
+                let bg_col = bg_palettes[palette as usize][bg_pixel as usize] as usize;
 
                 let mut sprite_pixel = 0;
                 let mut sprite_col = 0;
                 let mut sprite_priority = true;
-                for (i, x) in self.s_counters.into_iter().enumerate().rev() {
+                let mut sprite0_pixel = 0;
+                for (i, x) in self.s_counters.into_iter().enumerate() {
                     if x == 0 {
                         let palette = self.s_attrs[i] & 0x3;
                         let pixel = (self.s_pat_reg[i][1] & 1) << 1 | (self.s_pat_reg[i][0] & 1);
                         self.s_pat_reg[i][0] >>= 1;
                         self.s_pat_reg[i][1] >>= 1;
 
-                        if pixel != 0 {
+                        if i == 0 && self.sprite0_line {
+                            sprite0_pixel = pixel;
+                        }
+                        // the first opaque sprite wins, even if it's behind the background and a
+                        // later one isn't
+                        if pixel != 0 && sprite_pixel == 0 {
                             sprite_pixel = pixel;
-                            sprite_col = if self.sprites_enabled() {
-                                sprite_palettes[palette as usize][pixel as usize] as usize
-                            } else {
-                                0
-                            };
+                            sprite_col = sprite_palettes[palette as usize][pixel as usize] as usize;
                             sprite_priority = self.s_attrs[i] & (1 << 5) == 0;
                         }
                     }
@@ -526,29 +791,33 @@ impl Ppu {
                         *x -= 1;
                     }
                 }
-                //assert!(sprite_col < 64);
+                if !self.sprites_enabled() || (x < 8 && !self.sprites_left()) {
+                    sprite_pixel = 0;
+                    sprite0_pixel = 0;
+                }
 
-                let col = if bg_pixel == 0 && sprite_pixel == 0 {
+                // sprite 0 hit is only for the sprite that was first in oam, and never happens on
+                // the last pixel
+                if sprite0_pixel != 0 && bg_pixel != 0 && x != 255 {
+                    self.ppu_status |= 0x40;
+                }
+
+                let mut col = if bg_pixel == 0 && sprite_pixel == 0 {
                     bg_col
                 } else if bg_pixel == 0 && sprite_pixel != 0 {
                     sprite_col
                 } else if bg_pixel != 0 && sprite_pixel == 0 {
                     bg_col
+                } else if sprite_priority {
+                    sprite_col
                 } else {
-                    self.ppu_status |= 0x40;
-                    if sprite_priority {
-                        sprite_col
-                    } else {
-                        bg_col
-                    }
+                    bg_col
                 } % 64;
 
-                let red = PALETTE[col][0];
-                let green = PALETTE[col][1];
-                let blue = PALETTE[col][2];
-
-                let x = cycle as usize - 1;
-                let y = row as usize;
+                if self.greyscale() {
+                    // only the grey column of the palette
+                    col &= 0x30;
+                }
 
                 /*
                 if y == 0 && x == 2 {
@@ -556,23 +825,17 @@ impl Ppu {
                 }
                     */
 
-                let pitch = 3 * 256;
-                let offset = y * pitch + x * 3;
-                self.pixel_data[offset] = red;
-                self.pixel_data[offset + 1] = green;
-                self.pixel_data[offset + 2] = blue;
+                let mut emphasis = self.ppu_mask as u16 >> 5;
+                if self.region != Region::Ntsc {
+                    // the pal ppu has red and green the other way around
+                    emphasis = emphasis & 4 | (emphasis & 1) << 1 | (emphasis & 2) >> 1;
+                }
+                let emphasis = emphasis << 6;
+                self.drawing[y * 256 + x] = col as u16 | emphasis;
             }
 
-            if cycle % 8 == 0 {
-                let mut pal = self.l_attr;
-                let tile = tile + self.scroll_x as u32 / 8;
-                if tile & 2 == 2 {
-                    pal >>= 2;
-                }
-                if (row / 8) & 2 == 2 {
-                    pal >>= 4;
-                }
-                pal &= 3;
+            if cycle.is_multiple_of(8) {
+                let pal = self.l_attr;
                 self.b_pat_reg[0] |= (self.l_pt_low.reverse_bits() as u16) << 8;
                 self.b_pat_reg[1] |= (self.l_pt_high.reverse_bits() as u16) << 8;
                 self.b_pal_latch[0] = (pal & 1) != 0;
@@ -580,10 +843,14 @@ impl Ppu {
             }
         } else if (257..=320).contains(&cycle) {
             let rel_cycle = (cycle - 1) % 8;
-            let row = (row + 1) % 240;
             let s_oam_idx = (cycle as usize - 257) / 8;
+            if cycle == 257 {
+                self.sprite0_line = self.sprite0_next;
+            }
 
-            let y = self.secondary_oam[s_oam_idx * 4].wrapping_add(1);
+            let y = self.secondary_oam[s_oam_idx * 4];
+            // evaluation ran on this line for the next one, so this is the row inside the sprite
+            let line = row.wrapping_sub(y as u32);
             let tile_idx = self.secondary_oam[s_oam_idx * 4 + 1];
             let attr = self.secondary_oam[s_oam_idx * 4 + 2];
             let x = self.secondary_oam[s_oam_idx * 4 + 3];
@@ -591,14 +858,14 @@ impl Ppu {
             match rel_cycle {
                 1 => self.l_nametable = 0xAA, // garbage
                 3 => self.l_attr = 0xAA,      // garbage
-                5 => self.l_pt_low = self.read_pt(row, 0, false, Some(y), tile_idx),
-                7 => self.l_pt_high = self.read_pt(row, 0, true, Some(y), tile_idx),
+                5 => self.l_pt_low = self.read_sprite_pt(line, false, tile_idx, attr, chr),
+                7 => self.l_pt_high = self.read_sprite_pt(line, true, tile_idx, attr, chr),
                 _ => (),
             }
 
-            if cycle % 8 == 0 {
+            if cycle.is_multiple_of(8) {
                 let flip = attr & (1 << 6) != 0;
-                if y == 0xFF {
+                if s_oam_idx >= self.sprite_count as usize {
                     self.s_pat_reg[s_oam_idx][0] = 0;
                     self.s_pat_reg[s_oam_idx][1] = 0;
                 } else {
@@ -617,27 +884,19 @@ impl Ppu {
                 self.s_counters[s_oam_idx] = x;
             }
         } else if (321..=336).contains(&cycle) {
-            let tile = (cycle - 1) / 8 - 40;
-            let row = (row + 1) % 240;
-
             let rel_cycle = (cycle - 1) % 8;
 
             match rel_cycle {
                 1 => self.l_nametable = self.read_nametable(),
                 3 => self.l_attr = self.read_attr(),
-                5 => self.l_pt_low = self.read_pt(row, self.l_nametable, false, None, 0),
-                7 => self.l_pt_high = self.read_pt(row, self.l_nametable, true, None, 0),
+                5 => self.l_pt_low = self.read_pt(self.l_nametable, false, chr),
+                7 => self.l_pt_high = self.read_pt(self.l_nametable, true, chr),
                 _ => (),
             }
 
             // increment v horizontal scroll
-            if rel_cycle % 8 == 7 {
-                if self.v % 32 == 31 {
-                    self.v &= !0x1F;
-                    self.v ^= 0x400;
-                } else {
-                    self.v += 1;
-                }
+            if rel_cycle % 8 == 7 && self.rendering_enabled() {
+                self.increment_x();
             }
 
             self.b_pal_reg[0] >>= 1;
@@ -647,16 +906,8 @@ impl Ppu {
             self.b_pat_reg[0] >>= 1;
             self.b_pat_reg[1] >>= 1;
 
-            if cycle % 8 == 0 {
-                let mut pal = self.l_attr;
-                let tile = tile + self.scroll_x as u32 / 8;
-                if tile & 2 == 2 {
-                    pal >>= 2;
-                }
-                if (row / 8) & 2 == 2 {
-                    pal >>= 4;
-                }
-                pal &= 3;
+            if cycle.is_multiple_of(8) {
+                let pal = self.l_attr;
                 self.b_pat_reg[0] |= (self.l_pt_low.reverse_bits() as u16) << 8;
                 self.b_pat_reg[1] |= (self.l_pt_high.reverse_bits() as u16) << 8;
                 self.b_pal_latch[0] = (pal & 1) != 0;
@@ -667,35 +918,188 @@ impl Ppu {
         }
     }
 
-    pub fn cycle<M: MemoryDevice>(
-        &mut self,
-        texture: &mut Texture<'_>,
-        cpu: &mut Cpu<'_, M>,
-    ) -> anyhow::Result<()> {
-        if (0..240).contains(&(self.cycle / 341)) {
-            let row = self.cycle / 341;
-            let cycle = self.cycle % 341;
-            self.render_cycle(row, cycle);
-        } else if self.cycle / 341 == 261 {
-            let row = self.cycle / 341;
+    /// The whole $0000-$3FFF address space as the ppu sees it
+    pub fn vram(&self, chr: &impl ChrMemory) -> Vec<u8> {
+        (0..0x4000).map(|addr| self.mem_read(addr, chr)).collect()
+    }
+
+    /// The last finished frame, see `palette` for turning it into colours
+    pub fn frame(&self) -> &[u16] {
+        &self.frame
+    }
+
+    /// Runs a single dot, returning whether that finished a frame
+    pub fn cycle(&mut self, chr: &impl ChrMemory) -> bool {
+        self.warmup = self.warmup.saturating_sub(1);
+        let row = self.cycle / 341;
+        if (0..240).contains(&row) || row == self.pre_render_line() {
             let cycle = self.cycle % 341;
-            self.render_cycle(row, cycle);
+            self.render_cycle(row, cycle, chr);
         }
-        if self.cycle == 341 * 241 + 1 {
-            self.ppu_status |= 0x80;
-            if self.nmi_interrupt() {
-                cpu.nmi_interrupt();
+        if self.cycle == self.vblank_start() {
+            if !self.suppress_vbl {
+                self.ppu_status |= 0x80;
+                if self.nmi_interrupt() {
+                    self.nmi = true;
+                }
             }
+            self.suppress_vbl = false;
+        }
+
+        // on odd frames the last dot of the pre-render line gets skipped if rendering is on. only
+        // the ntsc ppu does this
+        if self.region == Region::Ntsc
+            && self.cycle == 341 * self.pre_render_line() + 339
+            && self.odd_frame
+            && self.rendering_enabled()
+        {
+            self.cycle += 1;
         }
 
         self.cycle += 1;
 
-        self.cycle %= 262 * 341;
+        self.cycle %= self.frame_dots();
 
         if self.cycle == 0 {
-            texture.update(None, &self.pixel_data, 256 * 3)?;
+            std::mem::swap(&mut self.drawing, &mut self.frame);
+            self.odd_frame = !self.odd_frame;
+            self.frame_count = self.frame_count.wrapping_add(1);
+        }
+        self.cycle == 0
+    }
+}
+
+// the frames aren't saved, the next one gets drawn over them anyway
+impl Savestate for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.cycle);
+        w.u8(self.ppu_ctrl);
+        w.u8(self.ppu_mask);
+        w.u8(self.ppu_status);
+        w.u8(self.oam_addr);
+        w.bool(self.w);
+        w.bytes(&self.oam);
+        w.bytes(&self.ciram);
+        w.bytes(&self.palette);
+        w.u8(self.read_buffer);
+        w.u16(self.v);
+        w.u16(self.t);
+        w.u8(self.x);
+        w.bytes(&self.secondary_oam);
+        w.u8(self.eval_stage as u8);
+        w.u8(self.eval_copy);
+        w.u8(self.s_oam_addr);
+        w.u8(self.oam_latch);
+        w.u8(self.sprite_count);
+        w.bool(self.sprite0_next);
+        w.bool(self.sprite0_line);
+        for i in 0..2 {
+            w.u16(self.b_pat_reg[i]);
+            w.u8(self.b_pal_reg[i]);
+            w.bool(self.b_pal_latch[i]);
         }
+        for i in 0..8 {
+            w.u8(self.s_pat_reg[i][0]);
+            w.u8(self.s_pat_reg[i][1]);
+            w.u8(self.s_attrs[i]);
+            w.u8(self.s_counters[i]);
+        }
+        w.u8(self.l_nametable);
+        w.u8(self.l_attr);
+        w.u8(self.l_pt_low);
+        w.u8(self.l_pt_high);
+        w.bool(self.nmi);
+        w.bool(self.suppress_vbl);
+        w.u32(self.warmup);
+        w.bool(self.odd_frame);
+        w.u32(self.frame_count);
+        w.u8(self.latch);
+        for refreshed in self.latch_refreshed {
+            w.u32(refreshed);
+        }
+    }
 
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()> {
+        self.cycle = r.u32()? % self.frame_dots();
+        self.ppu_ctrl = r.u8()?;
+        self.ppu_mask = r.u8()?;
+        self.ppu_status = r.u8()?;
+        self.oam_addr = r.u8()?;
+        self.w = r.bool()?;
+        r.bytes_into(&mut self.oam)?;
+        r.bytes_into(&mut self.ciram)?;
+        r.bytes_into(&mut self.palette)?;
+        self.read_buffer = r.u8()?;
+        self.v = r.u16()?;
+        self.t = r.u16()?;
+        self.x = r.u8()?;
+        r.bytes_into(&mut self.secondary_oam)?;
+        self.eval_stage = match r.u8()? {
+            0 => EvalStage::Search,
+            1 => EvalStage::Overflow,
+            2 => EvalStage::Done,
+            n => anyhow::bail!("bad sprite evaluation stage {n}"),
+        };
+        self.eval_copy = r.u8()?.min(4);
+        self.s_oam_addr = r.u8()?.min(32);
+        self.oam_latch = r.u8()?;
+        self.sprite_count = r.u8()?;
+        self.sprite0_next = r.bool()?;
+        self.sprite0_line = r.bool()?;
+        for i in 0..2 {
+            self.b_pat_reg[i] = r.u16()?;
+            self.b_pal_reg[i] = r.u8()?;
+            self.b_pal_latch[i] = r.bool()?;
+        }
+        for i in 0..8 {
+            self.s_pat_reg[i][0] = r.u8()?;
+            self.s_pat_reg[i][1] = r.u8()?;
+            self.s_attrs[i] = r.u8()?;
+            self.s_counters[i] = r.u8()?;
+        }
+        self.l_nametable = r.u8()?;
+        self.l_attr = r.u8()?;
+        self.l_pt_low = r.u8()?;
+        self.l_pt_high = r.u8()?;
+        self.nmi = r.bool()?;
+        self.suppress_vbl = r.bool()?;
+        self.warmup = r.u32()?;
+        self.odd_frame = r.bool()?;
+        self.frame_count = r.u32()?;
+        self.latch = r.u8()?;
+        for refreshed in self.latch_refreshed.iter_mut() {
+            *refreshed = r.u32()?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper0::Chr;
+
+    #[test]
+    fn scroll_then_address() {
+        let mut chr = Chr::new(vec![], 0);
+        let mut ppu = Ppu::new(Mirroring::Horizontal);
+        ppu.skip_to(0);
+
+        // a $2005 y with fine y 7 sets bit 14 of t, which the first $2006 write has to clear
+        ppu.write_addr(0x2005, 0x00, &mut chr);
+        ppu.write_addr(0x2005, 0x07, &mut chr);
+        assert_eq!(ppu.t >> 12, 7);
+        ppu.write_addr(0x2006, 0x20, &mut chr);
+        ppu.write_addr(0x2006, 0x00, &mut chr);
+        assert_eq!(ppu.v, 0x2000);
+
+        // the usual mid-frame split: nametable 1 from $2006, y 58 and x 16 from $2005, then the
+        // low byte of the address to $2006
+        ppu.write_addr(0x2006, 0x04, &mut chr);
+        ppu.write_addr(0x2005, 58, &mut chr);
+        ppu.write_addr(0x2005, 16, &mut chr);
+        ppu.write_addr(0x2006, 0xE2, &mut chr);
+        // fine y 2, nametable 1, coarse y 7, coarse x 2
+        assert_eq!(ppu.v, 0x24E2);
+    }
+}
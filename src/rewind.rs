@@ -0,0 +1,216 @@
+// rewinding: a history of save states that can be stepped back through
+//
+// only the newest state is kept whole, every older one is stored as the xor of it and the state
+// after it, which is mostly zeros since not a lot changes in a couple of frames. the zeros are
+// then squashed with a run length encoding
+
+use std::collections::VecDeque;
+
+#[derive(Debug)]
+pub struct Rewind {
+    /// How many frames to wait between snapshots
+    interval: u32,
+    /// Roughly how many bytes the history is allowed to use
+    budget: usize,
+    frames: u32,
+
+    latest: Option<Vec<u8>>,
+    // deltas[i] turns the state after it back into the state it was made from
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    /// Call once a frame, a snapshot is only taken every `interval` frames
+    pub fn frame(&mut self, snapshot: impl FnOnce() -> Vec<u8>) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(snapshot());
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == state.len() {
+                let delta = compress(latest.iter().zip(&state).map(|(a, b)| a ^ b));
+                self.used += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                // this shouldn't happen since states are always the same size for one rom, but
+                // the old history can't be used if it does
+                self.clear();
+            }
+        }
+        self.latest = Some(state);
+
+        while self.used + self.latest_len() > self.budget {
+            let Some(delta) = self.deltas.pop_front() else {
+                break;
+            };
+            self.used -= delta.len();
+        }
+    }
+
+    /// Steps back one snapshot and gives the state to load. Once the oldest snapshot is reached
+    /// it keeps being given back
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.as_ref()?;
+        let Some(delta) = self.deltas.pop_back() else {
+            return Some(latest.clone());
+        };
+        self.used -= delta.len();
+
+        let mut prev = latest.clone();
+        decompress_xor(&delta, &mut prev);
+        self.frames = 0;
+        self.latest.replace(prev)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.used = 0;
+        self.frames = 0;
+    }
+
+    /// How many snapshots can be stepped back through
+    pub fn len(&self) -> usize {
+        self.latest.is_some() as usize + self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    fn latest_len(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    while let Some(&b) = data.get(*pos) {
+        *pos += 1;
+        n |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    n
+}
+
+/// Encodes the bytes as pairs of (number of zeros, literal bytes)
+fn compress(bytes: impl Iterator<Item = u8>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut zeros = 0;
+    let mut literal = Vec::new();
+    for b in bytes {
+        if b == 0 {
+            if !literal.is_empty() {
+                write_varint(&mut out, zeros);
+                write_varint(&mut out, literal.len());
+                out.append(&mut literal);
+                zeros = 0;
+            }
+            zeros += 1;
+        } else {
+            literal.push(b);
+        }
+    }
+    if zeros != 0 || !literal.is_empty() {
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literal.len());
+        out.append(&mut literal);
+    }
+    out
+}
+
+/// Undoes `compress` and xors the result into `state`
+fn decompress_xor(data: &[u8], state: &mut [u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < data.len() {
+        i += read_varint(data, &mut pos);
+        let len = read_varint(data, &mut pos);
+        for &b in &data[pos..pos + len] {
+            state[i] ^= b;
+            i += 1;
+        }
+        pos += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_roundtrip() {
+        // long enough runs of zeros and literals to need more than one varint byte
+        let mut bytes = vec![0; 300];
+        bytes.extend(1..=255);
+        bytes.extend([0, 0, 0, 7, 0]);
+        let mut out = vec![0; bytes.len()];
+        decompress_xor(&compress(bytes.iter().copied()), &mut out);
+        assert_eq!(out, bytes);
+    }
+
+    #[test]
+    fn push_pop_roundtrip() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        let mut state = vec![0u8; 1000];
+        let mut history = Vec::new();
+        let mut seed = 1u32;
+        for _ in 0..20 {
+            // a few scattered bytes and one long run change, like a frame of a game
+            for _ in 0..8 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let i = (seed >> 8) as usize % state.len();
+                state[i] = (seed >> 24) as u8;
+            }
+            let start = (seed >> 4) as usize % 800;
+            state[start..start + 150].fill(seed as u8 | 1);
+            rewind.push(state.clone());
+            history.push(state.clone());
+        }
+        assert_eq!(rewind.len(), 20);
+
+        for expected in history.iter().rev() {
+            assert_eq!(rewind.pop().as_ref(), Some(expected));
+        }
+        // the oldest one keeps coming back
+        assert_eq!(rewind.pop().as_ref(), history.first());
+    }
+
+    #[test]
+    fn budget_drops_oldest() {
+        let mut rewind = Rewind::new(1, 64);
+        for i in 0..10u8 {
+            rewind.push(vec![i; 32]);
+        }
+        assert!(rewind.len() < 10);
+        assert_eq!(rewind.pop(), Some(vec![9; 32]));
+    }
+}
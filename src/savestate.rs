@@ -0,0 +1,143 @@
+// save states: a snapshot of the whole machine in a little binary format
+//
+// layout:
+//   "NESS"            magic
+//   u16               format version
+//   u32               crc32 of the rom the state was made with
+//   ...               the machine: cpu, then the mapper (rams, ppu, chr ram, controllers)
+//
+// everything is little endian
+
+use anyhow::{bail, Context};
+
+use crate::nes::Nes;
+
+const MAGIC: &[u8; 4] = b"NESS";
+pub const VERSION: u16 = 16;
+
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Writes a length prefixed byte string
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        StateReader { buf }
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            bail!("save state is truncated");
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> anyhow::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads a byte string into a buffer that must already be the right size
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> anyhow::Result<()> {
+        let b = self.bytes()?;
+        if b.len() != out.len() {
+            bail!(
+                "save state has a block of {} bytes where {} were expected",
+                b.len(),
+                out.len()
+            );
+        }
+        out.copy_from_slice(b);
+        Ok(())
+    }
+}
+
+pub fn save(nes: &Nes) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.buf.extend_from_slice(MAGIC);
+    w.u16(VERSION);
+    w.u32(nes.rom_hash());
+    nes.save_state(&mut w);
+    w.into_inner()
+}
+
+/// Loads a state into the machine, which is left alone if anything is wrong with the state
+pub fn load(data: &[u8], nes: &mut Nes) -> anyhow::Result<()> {
+    let mut r = StateReader::new(data);
+    if r.take(4).ok() != Some(MAGIC.as_slice()) {
+        bail!("not a save state");
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        bail!("save state is format version {version}, but only version {VERSION} is supported");
+    }
+    let hash = r.u32()?;
+    let rom_hash = nes.rom_hash();
+    if hash != rom_hash {
+        bail!("save state was made with a different rom (crc32 {hash:08x}, this rom is {rom_hash:08x})");
+    }
+    let mut loaded = nes.clone();
+    loaded.load_state(&mut r).context("save state is corrupt")?;
+    *nes = loaded;
+    Ok(())
+}
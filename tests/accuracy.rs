@@ -0,0 +1,247 @@
+// runs the usual accuracy test roms and prints a pass/fail matrix
+//
+// the roms aren't included, point NES_TEST_ROMS at a directory laid out like the nes-test-roms
+// collection (it defaults to test-roms/ in the crate). anything missing is skipped
+//
+// roms that report through $6000 are checked with the blargg protocol. older ones that only
+// draw their result on screen are checked against a frame hash, listed in expected-hashes.txt
+// in the rom directory as `<rom path> <frames> <crc32>` lines (make them with nes-headless)
+//
+// roms the emulator is known to fail are listed in expected-failures.txt next to this file. the
+// test only fails if something not on that list fails, or something on it starts passing (take
+// it off the list then)
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use nes_emulator::{blargg, Nes};
+
+#[derive(Debug, Clone, Copy)]
+enum Check {
+    /// nestest's automation mode: start at $C000 and look at the error codes in $02 and $03
+    Nestest,
+    Blargg,
+    FrameHash,
+}
+
+const SUITES: &[(&str, Check)] = &[
+    ("nestest.nes", Check::Nestest),
+    ("instr_test-v5", Check::Blargg),
+    ("ppu_vbl_nmi", Check::Blargg),
+    ("ppu_open_bus", Check::Blargg),
+    ("ppu_read_buffer", Check::Blargg),
+    ("oam_read", Check::Blargg),
+    ("apu_test", Check::Blargg),
+    ("sprite_hit_tests_2005.10.05", Check::FrameHash),
+    ("sprite_overflow_tests", Check::FrameHash),
+];
+
+const BLARGG_MAX_FRAMES: u32 = 60 * 60;
+const NESTEST_FRAMES: u32 = 60;
+
+const EXPECTED_FAILURES: &str = include_str!("expected-failures.txt");
+
+/// Whether a rom is on the expected failures list, where a line ending in / covers everything
+/// under that directory
+fn expected_to_fail(name: &str) -> bool {
+    EXPECTED_FAILURES
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .any(|line| {
+            if line.ends_with('/') {
+                name.starts_with(line)
+            } else {
+                name == line
+            }
+        })
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+fn rom_dir() -> PathBuf {
+    match std::env::var_os("NES_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("test-roms"),
+    }
+}
+
+/// Every .nes file in a suite. the blargg packs have both one rom with every test and a
+/// rom_singles directory with them split up, the singles give a more useful matrix
+fn suite_roms(path: &Path) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    let singles = path.join("rom_singles");
+    let dir = if singles.is_dir() { singles } else { path.to_path_buf() };
+
+    let mut roms = Vec::new();
+    let mut dirs = vec![dir];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("nes")) {
+                roms.push(path);
+            }
+        }
+    }
+    roms.sort();
+    roms
+}
+
+fn load_expected_hashes(dir: &Path) -> HashMap<String, (u32, u32)> {
+    let Ok(text) = std::fs::read_to_string(dir.join("expected-hashes.txt")) else {
+        return HashMap::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let rom = fields.next()?;
+            let frames = fields.next()?.parse().ok()?;
+            let crc = u32::from_str_radix(fields.next()?, 16).ok()?;
+            Some((rom.to_string(), (frames, crc)))
+        })
+        .collect()
+}
+
+fn run_rom(
+    path: &Path,
+    name: &str,
+    check: Check,
+    hashes: &HashMap<String, (u32, u32)>,
+) -> Outcome {
+    let rom = match std::fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => return Outcome::Fail(format!("couldn't read: {e}")),
+    };
+    let mut nes = match Nes::from_rom(&rom) {
+        Ok(nes) => nes,
+        Err(e) => return Outcome::Fail(format!("couldn't load: {e:#}")),
+    };
+
+    match check {
+        Check::Nestest => {
+            nes.set_pc(0xC000);
+            for _ in 0..NESTEST_FRAMES {
+                nes.run_frame();
+            }
+            let (official, unofficial) = (nes.ram()[2], nes.ram()[3]);
+            if official == 0 && unofficial == 0 {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!("error codes {official:#04x} {unofficial:#04x}"))
+            }
+        }
+        Check::Blargg => {
+            let result = blargg::run(&mut nes, BLARGG_MAX_FRAMES);
+            let message = result.message.trim().replace('\n', " / ");
+            match result.code {
+                Some(0) => Outcome::Pass,
+                Some(code) => Outcome::Fail(format!("code {code}: {message}")),
+                None => Outcome::Fail(format!("timed out: {message}")),
+            }
+        }
+        Check::FrameHash => {
+            let Some(&(frames, expected)) = hashes.get(name) else {
+                return Outcome::Skip("no expected hash".to_string());
+            };
+            for _ in 0..frames {
+                nes.run_frame();
+            }
+            let crc = nes.frame_hash();
+            if crc == expected {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!("frame hash {crc:08x}, expected {expected:08x}"))
+            }
+        }
+    }
+}
+
+#[test]
+fn accuracy_roms() {
+    let dir = rom_dir();
+    if !dir.is_dir() {
+        println!("no test roms in {}, skipping", dir.display());
+        return;
+    }
+    let hashes = load_expected_hashes(&dir);
+
+    let mut results = Vec::new();
+    for &(suite, check) in SUITES {
+        let path = dir.join(suite);
+        if !path.exists() {
+            results.push((suite.to_string(), Outcome::Skip("not found".to_string())));
+            continue;
+        }
+        for rom in suite_roms(&path) {
+            let name = rom
+                .strip_prefix(&dir)
+                .unwrap_or(&rom)
+                .to_string_lossy()
+                .replace('\\', "/");
+            // the emulator panics on things it doesn't support yet, which should just be a
+            // failure of that one rom
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                run_rom(&rom, &name, check, &hashes)
+            }))
+            .unwrap_or_else(|e| {
+                let msg = e
+                    .downcast_ref::<String>()
+                    .map(String::as_str)
+                    .or_else(|| e.downcast_ref::<&str>().copied())
+                    .unwrap_or("?");
+                Outcome::Fail(format!("panicked: {msg}"))
+            });
+            results.push((name, outcome));
+        }
+    }
+
+    let mut failed = 0;
+    let mut new_failures = Vec::new();
+    let mut now_passing = Vec::new();
+    for (name, outcome) in &results {
+        let expected = expected_to_fail(name);
+        match outcome {
+            Outcome::Pass => {
+                println!("PASS  {name}");
+                if expected {
+                    now_passing.push(name.as_str());
+                }
+            }
+            Outcome::Fail(why) => {
+                failed += 1;
+                let known = if expected { " (known)" } else { "" };
+                println!("FAIL  {name}  {why}{known}");
+                if !expected {
+                    new_failures.push(name.as_str());
+                }
+            }
+            Outcome::Skip(why) => println!("SKIP  {name}  {why}"),
+        }
+    }
+    let passed = results
+        .iter()
+        .filter(|(_, o)| matches!(o, Outcome::Pass))
+        .count();
+    println!("{passed} passed, {failed} failed");
+
+    assert!(
+        new_failures.is_empty(),
+        "test roms that aren't on the expected failures list failed: {new_failures:?}"
+    );
+    assert!(
+        now_passing.is_empty(),
+        "test roms on the expected failures list pass now, take them off it: {now_passing:?}"
+    );
+}